@@ -0,0 +1,89 @@
+#!/usr/bin/env bash
+# Times release builds of rum against each other on the benchmark programs.
+#
+#     scripts/bench.sh [-n RUNS] VARIANT...
+#
+# A variant is a git revision, optionally followed by a colon and the
+# flags to run it with, as in `979dc27`, `HEAD` or `HEAD:--fuse`. Each
+# revision is built once with `cargo build --release` in a temporary
+# worktree; set FEATURES to build with features, as in FEATURES=jit.
+#
+# Every round runs each program once on every variant, in turn, so that
+# drift on the machine hits all of them alike. For each program the script
+# prints the median wall time of every variant and the median of its
+# per-round ratios against the first variant. PROGRAMS overrides the
+# programs, which default to midmark.um and sandmark.umz.
+set -euo pipefail
+
+runs=5
+if [ "${1:-}" = -n ]; then
+    runs=$2
+    shift 2
+fi
+if [ $# -eq 0 ]; then
+    sed -n '2,15s/^# \{0,1\}//p' "$0" >&2
+    exit 2
+fi
+
+root=$(git rev-parse --show-toplevel)
+programs=${PROGRAMS:-"midmark.um sandmark.umz"}
+work=$(mktemp -d)
+trap 'for tree in "$work"/tree-*; do git -C "$root" worktree remove --force "$tree"; done 2>/dev/null; rm -rf "$work"' EXIT
+
+variants=("$@")
+binaries=()
+for variant in "${variants[@]}"; do
+    rev=${variant%%:*}
+    commit=$(git -C "$root" rev-parse --short "$rev")
+    binary=$work/rum-$commit
+    if [ ! -x "$binary" ]; then
+        git -C "$root" worktree add --quiet --detach "$work/tree-$commit" "$commit"
+        echo "building $rev ($commit)" >&2
+        # old revisions warn on newer compilers; only show a failed build
+        cargo build --quiet --release ${FEATURES:+--features "$FEATURES"} \
+            --manifest-path "$work/tree-$commit/Cargo.toml" \
+            --target-dir "$work/target-$commit" 2>"$work/build.log" || {
+            cat "$work/build.log" >&2
+            exit 1
+        }
+        cp "$work/target-$commit/release/rum" "$binary"
+    fi
+    binaries+=("$binary")
+done
+
+# wall time of one run, in seconds
+time_run() {
+    local start end
+    start=$(date +%s.%N)
+    "$@" </dev/null >/dev/null
+    end=$(date +%s.%N)
+    awk -v start="$start" -v end="$end" 'BEGIN { printf "%.3f\n", end - start }'
+}
+
+median() {
+    sort -g | awk '{ v[NR] = $1 } END { print (NR % 2) ? v[(NR + 1) / 2] : (v[NR / 2] + v[NR / 2 + 1]) / 2 }'
+}
+
+for program in $programs; do
+    for i in "${!variants[@]}"; do
+        : >"$work/times-$i"
+        : >"$work/ratios-$i"
+    done
+    for _ in $(seq "$runs"); do
+        first=
+        for i in "${!variants[@]}"; do
+            flags=
+            [[ ${variants[$i]} == *:* ]] && flags=${variants[$i]#*:}
+            # shellcheck disable=SC2086 # flags are meant to split
+            t=$(time_run "${binaries[$i]}" $flags "$root/$program")
+            echo "$t" >>"$work/times-$i"
+            first=${first:-$t}
+            awk -v t="$t" -v f="$first" 'BEGIN { printf "%.3f\n", t / f }' >>"$work/ratios-$i"
+        done
+    done
+    echo "$program, median of $runs runs:"
+    for i in "${!variants[@]}"; do
+        printf '  %-24s %8.3f s  x%.3f\n' "${variants[$i]}" \
+            "$(median <"$work/times-$i")" "$(median <"$work/ratios-$i")"
+    done
+done
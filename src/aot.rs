@@ -0,0 +1,389 @@
+use std::io::{self, Write};
+use std::ops::Range;
+use crate::cfg::{Cfg, Exit};
+use crate::parser::{self, Field, Instruction, OP, RA, RB, RC, RL, VL};
+use crate::um::EndOfInput;
+
+// Words of the image translated into each C function.
+const REGION: usize = 256;
+
+// Everything the translated program needs besides its own code: memory,
+// faults that print the way rum reports them, and an interpreter for the
+// regions of segment 0 that stop matching the image that was translated.
+const RUNTIME: &str = r#"
+/* every instruction gets a label whether or not anything jumps to it */
+#pragma GCC diagnostic ignored "-Wunused-label"
+
+static uint32_t **segs;
+static uint32_t *lens;
+static uint32_t nsegs, cap;
+/* free identifiers, reused last in first out */
+static uint32_t *free_ids;
+static uint32_t nfree;
+/* words of segment 0 that differ from the image, by region, and whether
+   segment 0 is no longer the image's size at all */
+static uint32_t dirty[REGIONS];
+static int foreign;
+
+static void print_registers(const uint32_t *r)
+{
+    fprintf(stderr, "; registers [%u, %u, %u, %u, %u, %u, %u, %u]\n",
+            r[0], r[1], r[2], r[3], r[4], r[5], r[6], r[7]);
+}
+
+static void __attribute__((noreturn, cold, format(printf, 4, 5)))
+fault(uint32_t pc, uint32_t word, const uint32_t *r, const char *format, ...)
+{
+    va_list args;
+    fflush(stdout);
+    fputs("Error: ", stderr);
+    va_start(args, format);
+    vfprintf(stderr, format, args);
+    va_end(args);
+    fprintf(stderr, " at pc %u (instruction 0x%08x)", pc, word);
+    print_registers(r);
+    exit(1);
+}
+
+static void __attribute__((noreturn, cold)) pc_out_of_bounds(uint32_t pc, const uint32_t *r)
+{
+    fflush(stdout);
+    fprintf(stderr, "Error: program counter %u is past the end of segment 0 (%u words)", pc, lens[0]);
+    print_registers(r);
+    exit(1);
+}
+
+/* Faults unless segs[seg][off] exists. regs is only evaluated on a fault. */
+#define CHECK(seg, off, pc, word, regs)                                             \
+    do {                                                                            \
+        if ((seg) >= nsegs || !segs[seg])                                           \
+            fault(pc, word, regs, "segment %u is not mapped", seg);                 \
+        if ((off) >= lens[seg])                                                     \
+            fault(pc, word, regs, "offset %u is out of bounds for segment %u (%u words)", \
+                  off, seg, lens[seg]);                                             \
+    } while (0)
+
+#define OUTPUT(value, pc, word, regs)                                               \
+    do {                                                                            \
+        if ((value) > 255)                                                          \
+            fault(pc, word, regs, "output value %u is greater than 255", value);    \
+        putchar(value);                                                             \
+    } while (0)
+
+/* Stores into segments go through here: GCC's access warnings otherwise walk
+   back from every store in a region over all of its labels, which can take
+   minutes even at -O0. */
+static void store(uint32_t seg, uint32_t off, uint32_t value)
+{
+    segs[seg][off] = value;
+}
+
+static void store_program(uint32_t off, uint32_t value)
+{
+    if (!foreign)
+        dirty[off / REGION] += (value != image[off]) - (segs[0][off] != image[off]);
+    segs[0][off] = value;
+}
+
+/* Whether the code translated for pc still matches segment 0. */
+static int translated(uint32_t pc)
+{
+    return !foreign && pc < IMAGE_LEN && !dirty[pc / REGION];
+}
+
+static uint32_t *allocate(uint32_t len, uint32_t pc, uint32_t word, const uint32_t *r)
+{
+    uint32_t *seg = calloc(len ? len : 1, sizeof *seg);
+    if (!seg)
+        fault(pc, word, r, "out of memory mapping %u words", len);
+    return seg;
+}
+
+static uint32_t map_segment(uint32_t len, uint32_t pc, uint32_t word, const uint32_t *r)
+{
+    uint32_t *seg = allocate(len, pc, word, r);
+    uint32_t id;
+    if (nfree) {
+        id = free_ids[--nfree];
+    } else {
+        if (nsegs == cap) {
+            cap = cap ? cap * 2 : 64;
+            segs = realloc(segs, cap * sizeof *segs);
+            lens = realloc(lens, cap * sizeof *lens);
+            free_ids = realloc(free_ids, cap * sizeof *free_ids);
+            if (!segs || !lens || !free_ids)
+                fault(pc, word, r, "out of memory mapping %u words", len);
+        }
+        id = nsegs++;
+    }
+    segs[id] = seg;
+    lens[id] = len;
+    return id;
+}
+
+static void unmap_segment(uint32_t id, uint32_t pc, uint32_t word, const uint32_t *r)
+{
+    if (id == 0)
+        fault(pc, word, r, "segment 0 cannot be unmapped");
+    if (id >= nsegs)
+        fault(pc, word, r, "segment %u is not mapped", id);
+    if (!segs[id])
+        fault(pc, word, r, "segment %u was already unmapped", id);
+    free(segs[id]);
+    segs[id] = NULL;
+    free_ids[nfree++] = id;
+}
+
+static void load_program(uint32_t id, uint32_t pc, uint32_t word, const uint32_t *r)
+{
+    uint32_t *copy, i;
+    if (id >= nsegs || !segs[id])
+        fault(pc, word, r, "segment %u is not mapped", id);
+    copy = allocate(lens[id], pc, word, r);
+    memcpy(copy, segs[id], lens[id] * sizeof *copy);
+    free(segs[0]);
+    segs[0] = copy;
+    lens[0] = lens[id];
+    memset(dirty, 0, sizeof dirty);
+    foreign = lens[0] != IMAGE_LEN;
+    if (!foreign)
+        for (i = 0; i < IMAGE_LEN; i++)
+            dirty[i / REGION] += copy[i] != image[i];
+}
+
+static uint32_t input(void)
+{
+    int c;
+    fflush(stdout);
+    c = getchar();
+    return c == EOF ? END_OF_INPUT : (uint32_t)c;
+}
+
+/* what interpret and the regions return to have main carry on from *next */
+#define LEAVE (-1)
+
+/* Runs segment 0 one word at a time from *next on, until a jump or running
+   on takes it to code that is still translated. */
+static int interpret(uint32_t *next, uint32_t *r)
+{
+    uint32_t pc = *next;
+    for (;;) {
+        uint32_t word, a, b, c;
+        if (pc >= lens[0])
+            pc_out_of_bounds(pc, r);
+        word = segs[0][pc];
+        a = RA(word);
+        b = RB(word);
+        c = RC(word);
+        switch (OP(word)) {
+        case 0: if (r[c]) r[a] = r[b]; break;
+        case 1: CHECK(r[b], r[c], pc, word, r); r[a] = segs[r[b]][r[c]]; break;
+        case 2:
+            CHECK(r[a], r[b], pc, word, r);
+            if (r[a])
+                segs[r[a]][r[b]] = r[c];
+            else
+                store_program(r[b], r[c]);
+            break;
+        case 3: r[a] = r[b] + r[c]; break;
+        case 4: r[a] = r[b] * r[c]; break;
+        case 5:
+            if (!r[c])
+                fault(pc, word, r, "division by zero");
+            r[a] = r[b] / r[c];
+            break;
+        case 6: r[a] = ~(r[b] & r[c]); break;
+        case 7: fflush(stdout); return 0;
+        case 8: r[b] = map_segment(r[c], pc, word, r); break;
+        case 9: unmap_segment(r[c], pc, word, r); break;
+        case 10: OUTPUT(r[c], pc, word, r); break;
+        case 11: r[c] = input(); break;
+        case 12:
+            if (r[b])
+                load_program(r[b], pc, word, r);
+            pc = r[c];
+            if (translated(pc)) {
+                *next = pc;
+                return LEAVE;
+            }
+            continue;
+        case 13: r[RL(word)] = VL(word); break;
+        default: fault(pc, word, r, "invalid opcode %u", OP(word));
+        }
+        pc++;
+        if (pc % REGION == 0 && translated(pc)) {
+            *next = pc;
+            return LEAVE;
+        }
+    }
+}
+
+#define REGS ((const uint32_t[]){r0, r1, r2, r3, r4, r5, r6, r7})
+#define SAVE                                                                        \
+    do {                                                                            \
+        r[0] = r0; r[1] = r1; r[2] = r2; r[3] = r3;                                 \
+        r[4] = r4; r[5] = r5; r[6] = r6; r[7] = r7;                                 \
+    } while (0)
+"#;
+
+// A C macro pulling a field out of a word.
+fn field_macro(name: &str, field: &Field) -> String {
+    format!("#define {}(w) (((w) >> {}) & {:#x}u)", name, field.lsb(), (1u64 << field.width()) - 1)
+}
+
+/// Translates a program image into a standalone C program that behaves like
+/// `rum` running it with the default flush policy.
+///
+/// The image is cut into regions of `REGION` words, each its own function,
+/// which keeps C compilers from spending minutes optimizing one function the
+/// size of the program. Every instruction becomes a label in its region
+/// followed by its C equivalent, so code falls through from one instruction
+/// to the next. Jumps whose targets the control-flow graph pins down within
+/// the region branch straight to them; the rest go through a switch on the
+/// program counter, and leave for `main` to call the region they land in.
+/// A region's code is only valid while its words of segment 0 match the
+/// image, so the program counts the words of each region that stores and
+/// LoadProgs have changed, and runs regions with any by an interpreter built
+/// into it. The interpreter hands back to translated code at the next jump
+/// or region boundary that reaches a region matching the image again.
+///
+/// This only pays off for programs that spend their time in the code they
+/// were loaded with. On midmark, which rewrites a few words of its own code,
+/// the program built with `cc -O2` runs in 0.22s to rum's 0.47s. It does not
+/// pay off on sandmark, which replaces segment 0 with a program it builds at
+/// run time: all of that runs on the built-in interpreter, and the
+/// translated sandmark takes 14.4s to rum's 10.4s. Either way, building the
+/// translation of a large image with gcc -O2 takes minutes, about 2m45s for
+/// midmark.
+pub fn translate(words: &[u32], end_of_input: EndOfInput, out: &mut impl Write) -> io::Result<()> {
+    writeln!(out, "/* Translated from a Universal Machine program by rum aot. */")?;
+    writeln!(out, "#include <stdarg.h>\n#include <stdint.h>\n#include <stdio.h>\n#include <stdlib.h>\n#include <string.h>\n")?;
+    for (name, field) in [("OP", &OP), ("RA", &RA), ("RB", &RB), ("RC", &RC), ("RL", &RL), ("VL", &VL)] {
+        writeln!(out, "{}", field_macro(name, field))?;
+    }
+    writeln!(out, "#define END_OF_INPUT {:#x}u", end_of_input.value())?;
+    let regions = words.len().div_ceil(REGION).max(1);
+    writeln!(out, "#define IMAGE_LEN {}u\n#define REGION {}u\n#define REGIONS {}", words.len(), REGION, regions)?;
+
+    writeln!(out, "\nstatic const uint32_t image[{}] = {{", words.len().max(1))?;
+    for line in words.chunks(8) {
+        let line: Vec<String> = line.iter().map(|word| format!("0x{:08x}", word)).collect();
+        writeln!(out, "    {},", line.join(", "))?;
+    }
+    writeln!(out, "}};")?;
+    out.write_all(RUNTIME.as_bytes())?;
+
+    let cfg = Cfg::build(words);
+    for region in 0..regions {
+        let start = region * REGION;
+        translate_region(words, &cfg, start..words.len().min(start + REGION), region, out)?;
+    }
+
+    writeln!(out, "\nstatic int (*const regions[])(uint32_t *, uint32_t *) = {{")?;
+    for region in 0..regions {
+        writeln!(out, "    region{},", region)?;
+    }
+    writeln!(out, "}};\n")?;
+    writeln!(out, "int main(void)\n{{")?;
+    writeln!(out, "    uint32_t r[8] = {{0}};")?;
+    writeln!(out, "    uint32_t pc = 0;")?;
+    writeln!(out, "    int status;")?;
+    writeln!(out, "    segs = malloc(sizeof *segs);\n    lens = malloc(sizeof *lens);\n    free_ids = malloc(sizeof *free_ids);")?;
+    writeln!(out, "    cap = nsegs = 1;")?;
+    writeln!(out, "    segs[0] = allocate({}, 0, 0, r);", words.len())?;
+    writeln!(out, "    lens[0] = {};", words.len())?;
+    writeln!(out, "    memcpy(segs[0], image, sizeof image);")?;
+    writeln!(out, "    do {{")?;
+    writeln!(out, "        if (pc >= lens[0])\n            pc_out_of_bounds(pc, r);")?;
+    writeln!(out, "        status = translated(pc) ? regions[pc / REGION](&pc, r) : interpret(&pc, r);")?;
+    writeln!(out, "    }} while (status == LEAVE);")?;
+    writeln!(out, "    return status;\n}}")
+}
+
+// One region as a C function that runs from `*next` on with the registers
+// in `r`. It returns LEAVE with `*next` set once control leaves the region
+// or a store changes it, and otherwise the status the program exits with.
+fn translate_region(words: &[u32], cfg: &Cfg, region: Range<usize>, index: usize, out: &mut impl Write) -> io::Result<()> {
+    writeln!(out, "\nstatic int region{}(uint32_t *next, uint32_t *r)\n{{", index)?;
+    writeln!(out, "    uint32_t r0 = r[0], r1 = r[1], r2 = r[2], r3 = r[3], r4 = r[4], r5 = r[5], r6 = r[6], r7 = r[7];")?;
+    writeln!(out, "    uint32_t pc = *next;")?;
+    writeln!(out, "dispatch:\n    switch (pc) {{")?;
+    for address in region.clone() {
+        writeln!(out, "    case {}: goto L{};", address, address)?;
+    }
+    writeln!(out, "    default: goto leave;\n    }}")?;
+    writeln!(out, "leave:\n    SAVE;\n    *next = pc;\n    return LEAVE;")?;
+    for address in region.clone() {
+        let word = words[address];
+        let text = parser::decode(word).map_or_else(|_| format!(".word {:#010x}", word), |i| i.to_string());
+        writeln!(out, "L{}: /* {} */", address, text)?;
+        for line in translate_word(words, cfg, &region, address) {
+            writeln!(out, "    {}", line)?;
+        }
+    }
+    // code running off the end of the region carries on in the next one, or
+    // faults just past the program
+    writeln!(out, "    pc = {};\n    goto leave;\n}}", region.end)
+}
+
+// The C statements for one word of the image.
+fn translate_word(words: &[u32], cfg: &Cfg, region: &Range<usize>, address: usize) -> Vec<String> {
+    let word = words[address];
+    let at = format!("{}, {:#010x}u", address, word);
+    let instruction = match parser::decode(word) {
+        Ok(instruction) => instruction,
+        Err(_) => return vec![format!("fault({}, REGS, \"invalid opcode %u\", {}u);", at, parser::op(word))],
+    };
+    match instruction {
+        Instruction::CMov { a, b, c } => vec![format!("if (r{}) r{} = r{};", c, a, b)],
+        Instruction::SegLoad { a, b, c } => vec![
+            format!("CHECK(r{}, r{}, {}, REGS);", b, c, at),
+            format!("r{} = segs[r{}][r{}];", a, b, c),
+        ],
+        Instruction::SegStore { a, b, c } => vec![
+            format!("CHECK(r{}, r{}, {}, REGS);", a, b, at),
+            format!("if (r{}) {{", a),
+            format!("    store(r{}, r{}, r{});", a, b, c),
+            "} else {".to_string(),
+            format!("    store_program(r{}, r{});", b, c),
+            format!("    if (dirty[{}]) {{", region.start / REGION),
+            format!("        pc = {};", address + 1),
+            "        goto leave;".to_string(),
+            "    }".to_string(),
+            "}".to_string(),
+        ],
+        Instruction::Add { a, b, c } => vec![format!("r{} = r{} + r{};", a, b, c)],
+        Instruction::Mul { a, b, c } => vec![format!("r{} = r{} * r{};", a, b, c)],
+        Instruction::Div { a, b, c } => vec![
+            format!("if (!r{}) fault({}, REGS, \"division by zero\");", c, at),
+            format!("r{} = r{} / r{};", a, b, c),
+        ],
+        Instruction::Nand { a, b, c } => vec![format!("r{} = ~(r{} & r{});", a, b, c)],
+        Instruction::Halt => vec!["fflush(stdout);".to_string(), "return 0;".to_string()],
+        Instruction::MapSeg { b, c } => vec![format!("r{} = map_segment(r{}, {}, REGS);", b, c, at)],
+        Instruction::UnmapSeg { c } => vec![format!("unmap_segment(r{}, {}, REGS);", c, at)],
+        Instruction::Output { c } => vec![format!("OUTPUT(r{}, {}, REGS);", c, at)],
+        Instruction::Input { c } => vec![format!("r{} = input();", c)],
+        Instruction::LoadProg { b, c } => {
+            let mut lines = vec![
+                format!("if (r{}) {{", b),
+                format!("    load_program(r{}, {}, REGS);", b, at),
+                format!("    pc = r{};", c),
+                "    goto leave;".to_string(),
+                "}".to_string(),
+            ];
+            // the analysis may be wrong about a target, but never about
+            // where one goes, so anything it missed still takes the switch,
+            // as do targets in other regions
+            if let Some(Exit::Jump { targets, .. }) = cfg.block_at(address).map(|block| &block.exit) {
+                for &target in targets.iter().filter(|&&t| region.contains(&(t as usize))) {
+                    lines.push(format!("if (r{} == {}u) goto L{};", c, target, target));
+                }
+            }
+            lines.push(format!("pc = r{};", c));
+            lines.push("goto dispatch;".to_string());
+            lines
+        }
+        Instruction::LoadVal { a, value } => vec![format!("r{} = {}u;", a, value)],
+    }
+}
@@ -0,0 +1,352 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, Write};
+use crate::parser::{encode, Instruction, MNEMONICS};
+
+/// An assembly error and the line it was found on.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct AsmError {
+    /// Counted from 1.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+// The most words a segment can hold, since offsets into one are 32 bits.
+const MAX_WORDS: u32 = u32::MAX;
+
+// What a line asks for once its labels are stripped.
+enum Statement {
+    Instruction { mnemonic: String, operands: Vec<String> },
+    /// `.word` with one expression per word.
+    Words(Vec<String>),
+    /// `.string`, one word per character.
+    Text(Vec<u32>),
+    /// `.space`, that many zero words.
+    Space(u32),
+}
+
+impl Statement {
+    fn len(&self) -> u32 {
+        match self {
+            Statement::Instruction { .. } => 1,
+            Statement::Words(words) => words.len() as u32,
+            Statement::Text(text) => text.len() as u32,
+            Statement::Space(len) => *len,
+        }
+    }
+}
+
+/// Turns UM assembly into the words of a program image.
+///
+/// Each line holds an optional `label:`, then an instruction or directive,
+/// then an optional comment starting with `;`, `#` or `//`. Instructions are
+/// written the way the disassembler prints them:
+///
+/// ```text
+/// cmov|load|store|add|mul|div|nand rA, rB, rC
+/// map rB, rC      loadprog rB, rC
+/// unmap rC        out rC        in rC
+/// loadval rA, VALUE
+/// halt
+/// ```
+///
+/// Values are decimal, `0x` hex or `0b` binary numbers, character
+/// constants such as `'a'` or `'\n'`, labels, or names defined with
+/// `.equ NAME, VALUE`, optionally plus or minus a number. `.word V, ...`
+/// emits words, `.string "text"` emits one word per character and
+/// `.space N` emits N zero words.
+pub fn assemble(source: &str) -> Result<Vec<u32>, AsmError> {
+    assemble_lines(source.lines().enumerate().map(|(index, line)| (index + 1, line.to_string())))
+}
+
+// Assembles lines tagged with the source line they came from, so that a
+// front end can hand over lines it generated.
+pub(crate) fn assemble_lines(lines: impl IntoIterator<Item = (usize, String)>) -> Result<Vec<u32>, AsmError> {
+    let mut symbols = HashMap::new();
+    let mut statements = Vec::new();
+    let mut address: u32 = 0;
+    for (number, line) in lines {
+        let error = |message: String| AsmError { line: number, message };
+        let mut rest = strip_comment(&line).trim();
+        while let Some((label, after)) = split_label(rest) {
+            define(&mut symbols, label, address).map_err(error)?;
+            rest = after.trim();
+        }
+        if rest.is_empty() {
+            continue;
+        }
+        let (name, operands) = match rest.split_once(char::is_whitespace) {
+            Some((name, operands)) => (name, operands.trim()),
+            None => (rest, ""),
+        };
+        let statement = match name {
+            ".word" => Statement::Words(split_operands(operands)),
+            ".string" => Statement::Text(parse_string(operands).map_err(error)?),
+            ".space" => {
+                let len = evaluate(operands, &symbols).map_err(error)?;
+                if len > MAX_WORDS - address {
+                    return Err(error(format!(".space {} does not fit in a segment", len)));
+                }
+                Statement::Space(len)
+            }
+            ".equ" => {
+                let operands = split_operands(operands);
+                let [name, value] = operands.as_slice() else {
+                    return Err(error(".equ takes a name and a value".to_string()));
+                };
+                let value = evaluate(value, &symbols).map_err(error)?;
+                define(&mut symbols, name, value).map_err(error)?;
+                continue;
+            }
+            _ if name.starts_with('.') => return Err(error(format!("unknown directive {}", name))),
+            _ => Statement::Instruction { mnemonic: name.to_lowercase(), operands: split_operands(operands) },
+        };
+        address = address
+            .checked_add(statement.len())
+            .ok_or_else(|| error("the program does not fit in a segment".to_string()))?;
+        statements.push((number, statement));
+    }
+
+    let mut words = Vec::new();
+    if words.try_reserve_exact(address as usize).is_err() {
+        let last = statements.last().map_or(0, |(number, _)| *number);
+        return Err(AsmError { line: last, message: format!("not enough memory for a program of {} words", address) });
+    }
+    for (number, statement) in statements {
+        let error = |message: String| AsmError { line: number, message };
+        match statement {
+            Statement::Instruction { mnemonic, operands } => {
+                words.push(encode(&instruction(&mnemonic, &operands, &symbols).map_err(error)?))
+            }
+            Statement::Words(values) => {
+                for value in values {
+                    words.push(evaluate(&value, &symbols).map_err(error)?);
+                }
+            }
+            Statement::Text(text) => words.extend(text),
+            Statement::Space(len) => words.extend(std::iter::repeat_n(0, len as usize)),
+        }
+    }
+    Ok(words)
+}
+
+/// Writes a program image the way `rumload::load` reads it: every word
+/// big-endian.
+pub fn write_image(words: &[u32], writer: &mut impl Write) -> io::Result<()> {
+    for word in words {
+        writer.write_all(&word.to_be_bytes())?;
+    }
+    Ok(())
+}
+
+fn define(symbols: &mut HashMap<String, u32>, name: &str, value: u32) -> Result<(), String> {
+    if !is_identifier(name) {
+        return Err(format!("bad name {}", name));
+    }
+    if symbols.insert(name.to_string(), value).is_some() {
+        return Err(format!("{} is defined twice", name));
+    }
+    Ok(())
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.')
+        && parse_register(name).is_none()
+}
+
+// A leading `name:` and what follows it.
+pub(crate) fn split_label(line: &str) -> Option<(&str, &str)> {
+    let (label, rest) = line.split_once(':')?;
+    // `name :=` is an assignment in the umasm dialect, not a label
+    (is_identifier(label.trim()) && !rest.starts_with('=')).then_some((label.trim(), rest))
+}
+
+// Cuts a line at the first comment outside quotes.
+pub(crate) fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            _ if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(q), _) if c == q => quote = None,
+            (None, '\'' | '"') => quote = Some(c),
+            (None, ';' | '#') => return &line[..i],
+            (None, '/') if line[i + 1..].starts_with('/') => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+// Splits operands at the commas outside quotes.
+pub(crate) fn split_operands(text: &str) -> Vec<String> {
+    if text.trim().is_empty() {
+        return vec![];
+    }
+    let mut operands = vec![String::new()];
+    let mut quote = None;
+    let mut escaped = false;
+    for c in text.chars() {
+        match (quote, c) {
+            _ if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(q), _) if c == q => quote = None,
+            (None, '\'' | '"') => quote = Some(c),
+            (None, ',') => {
+                operands.push(String::new());
+                continue;
+            }
+            _ => {}
+        }
+        operands.last_mut().unwrap().push(c);
+    }
+    operands.iter().map(|operand| operand.trim().to_string()).collect()
+}
+
+pub(crate) fn parse_register(text: &str) -> Option<u8> {
+    match text.strip_prefix('r')?.parse::<u8>() {
+        Ok(r) if r < 8 => Some(r),
+        _ => None,
+    }
+}
+
+fn register(text: &str) -> Result<u8, String> {
+    parse_register(text).ok_or_else(|| format!("expected a register r0 to r7, found {:?}", text))
+}
+
+// Reads the characters of a quoted literal, resolving escapes.
+fn unquote(text: &str, quote: char) -> Result<Vec<u32>, String> {
+    let inner = text
+        .strip_prefix(quote)
+        .and_then(|t| t.strip_suffix(quote))
+        .filter(|_| text.len() >= 2)
+        .ok_or_else(|| format!("unterminated literal {}", text))?;
+    let mut values = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        let value = match c {
+            '\\' => match chars.next() {
+                Some('n') => 10,
+                Some('t') => 9,
+                Some('r') => 13,
+                Some('0') => 0,
+                Some('x') => {
+                    let hex: String = chars.by_ref().take(2).collect();
+                    u32::from_str_radix(&hex, 16).map_err(|_| format!("bad escape \\x{}", hex))?
+                }
+                Some(c @ ('\\' | '\'' | '"')) => c as u32,
+                other => return Err(format!("bad escape \\{}", other.map_or(String::new(), String::from))),
+            },
+            c => c as u32,
+        };
+        values.push(value);
+    }
+    Ok(values)
+}
+
+fn parse_string(text: &str) -> Result<Vec<u32>, String> {
+    unquote(text.trim(), '"')
+}
+
+fn parse_number(text: &str) -> Option<u64> {
+    if let Some(hex) = text.strip_prefix("0x") {
+        u64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = text.strip_prefix("0b") {
+        u64::from_str_radix(binary, 2).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+// Where the last `+` or `-` outside character constants is.
+fn last_operator(text: &str) -> Option<usize> {
+    let mut last = None;
+    let mut quoted = false;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '\'' => quoted = !quoted,
+            '+' | '-' if !quoted => last = Some(i),
+            _ => {}
+        }
+    }
+    last
+}
+
+// Evaluates a value: a number, a character or a symbol, plus or minus a number.
+pub(crate) fn evaluate(text: &str, symbols: &HashMap<String, u32>) -> Result<u32, String> {
+    let text = text.trim();
+    if text.is_empty() {
+        return Err("missing value".to_string());
+    }
+    if let Some(i) = last_operator(text).filter(|&i| i > 0) {
+        let base = evaluate(&text[..i], symbols)?;
+        let offset = evaluate(&text[i + 1..], symbols)?;
+        return Ok(if text[i..].starts_with('+') { base.wrapping_add(offset) } else { base.wrapping_sub(offset) });
+    }
+    if text.len() >= 3 && text.starts_with('\'') && text.ends_with('\'') {
+        return match unquote(text, '\'')?.as_slice() {
+            [value] => Ok(*value),
+            _ => Err(format!("{} is not a single character", text)),
+        };
+    }
+    if let Some(negated) = text.strip_prefix('-') {
+        return evaluate(negated, symbols).map(u32::wrapping_neg);
+    }
+    if let Some(value) = parse_number(text) {
+        return u32::try_from(value).map_err(|_| format!("{} does not fit in a word", text));
+    }
+    symbols.get(text).copied().ok_or_else(|| format!("undefined symbol {}", text))
+}
+
+fn instruction(mnemonic: &str, operands: &[String], symbols: &HashMap<String, u32>) -> Result<Instruction, String> {
+    let opcode = MNEMONICS
+        .iter()
+        .position(|&m| m == mnemonic)
+        .ok_or_else(|| format!("unknown instruction {}", mnemonic))?;
+    let expected = match opcode {
+        0..=6 => 3,
+        7 => 0,
+        8 | 12 | 13 => 2,
+        _ => 1,
+    };
+    if operands.len() != expected {
+        return Err(format!("{} takes {} operands, found {}", mnemonic, expected, operands.len()));
+    }
+    let r = |n: usize| register(&operands[n]);
+    Ok(match opcode {
+        0 => Instruction::CMov { a: r(0)?, b: r(1)?, c: r(2)? },
+        1 => Instruction::SegLoad { a: r(0)?, b: r(1)?, c: r(2)? },
+        2 => Instruction::SegStore { a: r(0)?, b: r(1)?, c: r(2)? },
+        3 => Instruction::Add { a: r(0)?, b: r(1)?, c: r(2)? },
+        4 => Instruction::Mul { a: r(0)?, b: r(1)?, c: r(2)? },
+        5 => Instruction::Div { a: r(0)?, b: r(1)?, c: r(2)? },
+        6 => Instruction::Nand { a: r(0)?, b: r(1)?, c: r(2)? },
+        7 => Instruction::Halt,
+        8 => Instruction::MapSeg { b: r(0)?, c: r(1)? },
+        9 => Instruction::UnmapSeg { c: r(0)? },
+        10 => Instruction::Output { c: r(0)? },
+        11 => Instruction::Input { c: r(0)? },
+        12 => Instruction::LoadProg { b: r(0)?, c: r(1)? },
+        _ => {
+            let value = evaluate(&operands[1], symbols)?;
+            if value >= 1 << 25 {
+                return Err(format!("loadval takes values below 2^25, found {}", value));
+            }
+            Instruction::LoadVal { a: r(0)?, value }
+        }
+    })
+}
@@ -0,0 +1,188 @@
+//! The jit's bookkeeping for the blocks it compiles from segment 0: which
+//! address has a block, which words each block was built from, and throwing
+//! away the blocks a store or a LoadProg has made stale.
+//!
+//! Blocks are kept per version of segment 0, and the tables of the last few
+//! versions are kept around, so a program that switches back and forth
+//! between segments with LoadProg finds its blocks where it left them.
+
+use std::cell::Cell;
+
+use crate::segments::SegmentManager;
+
+/// Longest block built, in instructions.
+pub(crate) const MAX_BLOCK: usize = 256;
+
+// Tables kept for versions of segment 0 other than the current one.
+const KEPT_TABLES: usize = 4;
+
+/// A straight run of instructions from segment 0, in some backend's form.
+pub(crate) trait Block {
+    /// What other blocks need in order to jump straight into this one, for
+    /// backends whose blocks chain; the default value means no block.
+    type Entry: Copy + Default;
+
+    /// How many words of segment 0 the block was built from, starting at
+    /// its own address.
+    fn len(&self) -> usize;
+
+    fn entry(&self) -> Self::Entry;
+}
+
+// only the jit counts how often execution reaches an address
+pub(crate) enum Slot<B> {
+    /// No block yet; counts the times execution reached the address.
+    Cold(u32),
+    Built(Box<B>),
+    /// No block can start here.
+    Never,
+}
+
+// The blocks of one version of segment 0, by starting address.
+struct Table<B: Block> {
+    version: u64,
+    slots: Vec<Slot<B>>,
+    entries: Vec<B::Entry>,
+    // how many blocks cover each word, so that stores to data in segment 0
+    // can be told apart from stores to code without a search; an address
+    // where no block can start counts as covered, so that it is looked at
+    // again once stored to
+    coverage: Vec<u16>,
+}
+
+impl<B: Block> Table<B> {
+    fn new(version: u64, len: usize) -> Self {
+        let mut slots = Vec::with_capacity(len);
+        slots.resize_with(len, || Slot::Cold(0));
+        Self { version, slots, entries: vec![B::Entry::default(); len], coverage: vec![0; len] }
+    }
+
+    // Forgets every block that covers a word that changed.
+    fn invalidate(&mut self, offset: usize) {
+        if let Slot::Never = self.slots[offset] {
+            self.slots[offset] = Slot::Cold(0);
+            self.coverage[offset] -= 1;
+        }
+        if self.coverage[offset] == 0 {
+            return;
+        }
+        for start in offset.saturating_sub(MAX_BLOCK)..=offset {
+            if let Slot::Built(block) = &self.slots[start] {
+                let end = start + block.len();
+                if end > offset {
+                    for count in &mut self.coverage[start..end] {
+                        *count -= 1;
+                    }
+                    self.slots[start] = Slot::Cold(0);
+                    self.entries[start] = B::Entry::default();
+                }
+            }
+        }
+    }
+}
+
+/// The blocks of segment 0 some backend has built, by starting address.
+pub(crate) struct BlockIndex<B: Block> {
+    table: Option<Table<B>>,
+    // most recently left last
+    kept: Vec<Table<B>>,
+    // how many stores into segment 0 the blocks are up to date with; a Cell
+    // so that a store made by one of the blocks can move it on while the
+    // blocks are borrowed
+    seen: Cell<u64>,
+}
+
+impl<B: Block> Default for BlockIndex<B> {
+    fn default() -> Self {
+        Self { table: None, kept: vec![], seen: Cell::new(0) }
+    }
+}
+
+impl<B: Block> BlockIndex<B> {
+    /// Catches up with segment 0: switches to the blocks of its version, and
+    /// throws away the blocks covering words stored to since the last call.
+    pub(crate) fn sync(&mut self, segments: &mut SegmentManager) {
+        let version = segments.program_version();
+        if self.table.as_ref().map(|table| table.version) != Some(version) {
+            let kept = self.kept.iter().position(|table| table.version == version);
+            let table = match kept {
+                Some(index) => self.kept.remove(index),
+                None => Table::new(version, segments.program().len()),
+            };
+            if let Some(old) = self.table.replace(table) {
+                self.kept.push(old);
+                if self.kept.len() > KEPT_TABLES {
+                    self.kept.remove(0);
+                }
+            }
+        }
+        let table = self.table.as_mut().unwrap();
+        match segments.program_writes(self.seen.get()) {
+            Some(offsets) => {
+                for &offset in offsets {
+                    table.invalidate(offset as usize);
+                }
+            }
+            None => *table = Table::new(version, segments.program().len()),
+        }
+        self.seen.set(segments.program_write_count());
+    }
+
+    /// Counts the stores into segment 0 so far as seen, for a block whose
+    /// own store turned out not to touch any block. Every earlier store
+    /// must have been seen already.
+    pub(crate) fn skip_writes(&self, segments: &SegmentManager) {
+        self.seen.set(segments.program_write_count());
+    }
+
+    /// Throws away every block of every version.
+        pub(crate) fn clear(&mut self) {
+        self.table = None;
+        self.kept.clear();
+    }
+
+    fn table(&self) -> &Table<B> {
+        self.table.as_ref().expect("sync comes first")
+    }
+
+    pub(crate) fn get(&self, pc: usize) -> Option<&Slot<B>> {
+        self.table().slots.get(pc)
+    }
+
+        pub(crate) fn get_mut(&mut self, pc: usize) -> Option<&mut Slot<B>> {
+        self.table.as_mut().expect("sync comes first").slots.get_mut(pc)
+    }
+
+    pub(crate) fn is_built(&self, pc: usize) -> bool {
+        matches!(self.get(pc), Some(Slot::Built(_)))
+    }
+
+    /// Puts the block built at `pc` in place, or records that none can be.
+    pub(crate) fn insert(&mut self, pc: usize, block: Option<B>) {
+        let table = self.table.as_mut().expect("sync comes first");
+        table.slots[pc] = match block {
+            Some(block) => {
+                for count in &mut table.coverage[pc..pc + block.len()] {
+                    *count += 1;
+                }
+                table.entries[pc] = block.entry();
+                Slot::Built(Box::new(block))
+            }
+            None => {
+                table.coverage[pc] += 1;
+                Slot::Never
+            }
+        };
+    }
+
+    /// How many blocks cover each word of segment 0, counting an address
+    /// where no block can start as covered by one.
+    pub(crate) fn coverage(&self) -> &[u16] {
+        &self.table().coverage
+    }
+
+    /// The entry of the block at each address of segment 0.
+        pub(crate) fn entries(&self) -> &[B::Entry] {
+        &self.table().entries
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::{BTreeSet, VecDeque};
+use std::io::{self, Write};
+use crate::parser::{self, Instruction};
+
+// Most values a register is tracked as possibly holding before it is
+// treated as unknown.
+const MAX_VALUES: usize = 8;
+
+/// How control leaves a basic block.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Exit {
+    /// Into the block that starts right after it.
+    FallThrough,
+    /// The block ends in a Halt.
+    Halt,
+    /// The block ends in a word that does not decode, so the machine faults.
+    Fault,
+    /// A LoadProg within segment 0. `targets` holds every address the
+    /// analysis found; `unresolved` is set when there may be others, or
+    /// when the LoadProg may replace segment 0 instead.
+    Jump { targets: Vec<u32>, unresolved: bool },
+    /// A LoadProg that replaces segment 0, so nothing is known about where
+    /// it lands.
+    ProgramLoad,
+    /// The last word of the program, when it neither jumps nor halts.
+    OffTheEnd,
+}
+
+/// A run of instructions entered only at the top and left only at the bottom.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct BasicBlock {
+    pub start: usize,
+    /// One past the last instruction.
+    pub end: usize,
+    pub exit: Exit,
+    /// Start addresses of the blocks control can move to.
+    pub successors: Vec<usize>,
+    /// Whether a path leads here from address 0. Once an unresolved jump is
+    /// reachable, every block whose address reachable code loads as a
+    /// constant counts as one of its targets.
+    pub reachable: bool,
+}
+
+/// The control-flow graph of a program image, as far as static analysis
+/// of segment 0 can tell. Code that rewrites segment 0 is not followed.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Cfg {
+    /// Ordered by address, covering every word.
+    pub blocks: Vec<BasicBlock>,
+}
+
+// What a register may hold at a point in a block.
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Value {
+    Unknown,
+    OneOf(BTreeSet<u32>),
+}
+
+impl Value {
+    fn constant(value: u32) -> Self {
+        Value::OneOf(BTreeSet::from([value]))
+    }
+
+    fn union(&self, other: &Value) -> Value {
+        match (self, other) {
+            (Value::OneOf(a), Value::OneOf(b)) if a.len() + b.len() <= MAX_VALUES => {
+                Value::OneOf(a.union(b).copied().collect())
+            }
+            _ => Value::Unknown,
+        }
+    }
+}
+
+fn ends_block(word: u32) -> bool {
+    matches!(parser::decode(word), Err(_) | Ok(Instruction::Halt) | Ok(Instruction::LoadProg { .. }))
+}
+
+// The register an instruction writes, if any.
+fn destination(instruction: &Instruction) -> Option<u8> {
+    match *instruction {
+        Instruction::CMov { a, .. }
+        | Instruction::SegLoad { a, .. }
+        | Instruction::Add { a, .. }
+        | Instruction::Mul { a, .. }
+        | Instruction::Div { a, .. }
+        | Instruction::Nand { a, .. }
+        | Instruction::LoadVal { a, .. } => Some(a),
+        Instruction::MapSeg { b, .. } => Some(b),
+        Instruction::Input { c } => Some(c),
+        _ => None,
+    }
+}
+
+// What the registers hold where blocks start. The machine starts with every
+// register 0, but once a jump may land on address 0 again only the
+// registers no reachable instruction writes are still known to be.
+struct Entry {
+    first: [Value; 8],
+    any: [Value; 8],
+}
+
+impl Entry {
+    fn new(written: [bool; 8]) -> Self {
+        let any = written.map(|written| if written { Value::Unknown } else { Value::constant(0) });
+        Entry { first: std::array::from_fn(|_| Value::constant(0)), any }
+    }
+
+    fn registers(&self, start: usize, reentered: bool) -> [Value; 8] {
+        if start == 0 && !reentered {
+            self.first.clone()
+        } else {
+            self.any.clone()
+        }
+    }
+}
+
+// Follows the constants loaded in a block to see where its final LoadProg
+// can go. A conditional move merges both values it chooses between.
+fn exit_of(words: &[u32], start: usize, end: usize, mut registers: [Value; 8]) -> Exit {
+    for &word in &words[start..end - 1] {
+        match parser::decode(word) {
+            Ok(Instruction::LoadVal { a, value }) => registers[a as usize] = Value::constant(value),
+            Ok(Instruction::CMov { a, b, .. }) => {
+                registers[a as usize] = registers[a as usize].union(&registers[b as usize])
+            }
+            Ok(instruction) => {
+                if let Some(r) = destination(&instruction) {
+                    registers[r as usize] = Value::Unknown;
+                }
+            }
+            Err(_) => {}
+        }
+    }
+    let last = words[end - 1];
+    match parser::decode(last) {
+        Err(_) => Exit::Fault,
+        Ok(Instruction::Halt) => Exit::Halt,
+        Ok(Instruction::LoadProg { b, c }) => {
+            let stays = match &registers[b as usize] {
+                Value::OneOf(segments) if !segments.contains(&0) => return Exit::ProgramLoad,
+                Value::OneOf(segments) => segments.len() == 1,
+                Value::Unknown => false,
+            };
+            // a segment register that may be nonzero may load a program
+            // instead of jumping, which leaves the jump unresolved
+            match &registers[c as usize] {
+                Value::OneOf(targets) => Exit::Jump { targets: targets.iter().copied().collect(), unresolved: !stays },
+                Value::Unknown => Exit::Jump { targets: vec![], unresolved: true },
+            }
+        }
+        Ok(_) if end == words.len() => Exit::OffTheEnd,
+        Ok(_) => Exit::FallThrough,
+    }
+}
+
+impl Cfg {
+    /// Splits a program into basic blocks and links them up.
+    pub fn build(words: &[u32]) -> Self {
+        // start from registers nothing writes and take in the writes of the
+        // code found reachable, which can only make more code reachable,
+        // until no more registers turn up; words setting bits no instruction
+        // uses are taken for data, such as the jump tables unresolved jumps
+        // make reachable
+        let mut written = [false; 8];
+        loop {
+            let cfg = Self::build_assuming(words, written);
+            let before = written;
+            for block in cfg.blocks.iter().filter(|b| b.reachable) {
+                for &word in &words[block.start..block.end] {
+                    let Ok(instruction) = parser::decode(word) else {
+                        continue;
+                    };
+                    if parser::encode(&instruction) != word {
+                        continue;
+                    }
+                    if let Some(r) = destination(&instruction) {
+                        written[r as usize] = true;
+                    }
+                }
+            }
+            if written == before {
+                return cfg;
+            }
+        }
+    }
+
+    // build, taking the registers not in `written` to hold 0 throughout.
+    fn build_assuming(words: &[u32], written: [bool; 8]) -> Self {
+        if words.is_empty() {
+            return Cfg { blocks: vec![] };
+        }
+        // a block starts at 0, after every block-ending word and at every
+        // resolved jump target; targets can split blocks and so change what
+        // other blocks resolve, so repeat until nothing new turns up
+        let mut leaders: BTreeSet<usize> = BTreeSet::from([0]);
+        for (address, &word) in words.iter().enumerate() {
+            if ends_block(word) && address + 1 < words.len() {
+                leaders.insert(address + 1);
+            }
+        }
+        let entry = Entry::new(written);
+        // whether some jump lands on address 0
+        let mut reentered = false;
+        loop {
+            let before = (leaders.len(), reentered);
+            let starts: Vec<usize> = leaders.iter().copied().collect();
+            for (i, &start) in starts.iter().enumerate() {
+                let end = starts.get(i + 1).copied().unwrap_or(words.len());
+                if let Exit::Jump { targets, .. } = exit_of(words, start, end, entry.registers(start, reentered)) {
+                    reentered |= targets.contains(&0);
+                    leaders.extend(targets.iter().map(|&t| t as usize).filter(|&t| t < words.len()));
+                }
+            }
+            if (leaders.len(), reentered) == before {
+                break;
+            }
+        }
+
+        let starts: Vec<usize> = leaders.into_iter().collect();
+        let mut blocks: Vec<BasicBlock> = starts
+            .iter()
+            .enumerate()
+            .map(|(i, &start)| {
+                let end = starts.get(i + 1).copied().unwrap_or(words.len());
+                let exit = exit_of(words, start, end, entry.registers(start, reentered));
+                let successors = match &exit {
+                    Exit::FallThrough => vec![end],
+                    Exit::Jump { targets, .. } => {
+                        targets.iter().map(|&t| t as usize).filter(|&t| t < words.len()).collect()
+                    }
+                    _ => vec![],
+                };
+                BasicBlock { start, end, exit, successors, reachable: false }
+            })
+            .collect();
+
+        // an unresolved jump could go anywhere, but in practice it goes to
+        // an address the program loaded as a constant, such as a return address
+        let mut queue = VecDeque::from([0]);
+        let mut address_taken = BTreeSet::new();
+        let mut unresolved = false;
+        blocks[0].reachable = true;
+        loop {
+            while let Some(i) = queue.pop_front() {
+                let block = &blocks[i];
+                unresolved |= matches!(block.exit, Exit::Jump { unresolved: true, .. } | Exit::ProgramLoad);
+                address_taken.extend(words[block.start..block.end].iter().filter_map(|&word| match parser::decode(word) {
+                    Ok(Instruction::LoadVal { value, .. }) => Some(value as usize),
+                    _ => None,
+                }));
+                for successor in block.successors.clone() {
+                    let j = blocks.partition_point(|b| b.start <= successor) - 1;
+                    if !blocks[j].reachable {
+                        blocks[j].reachable = true;
+                        queue.push_back(j);
+                    }
+                }
+            }
+            if unresolved {
+                for (j, block) in blocks.iter_mut().enumerate() {
+                    if !block.reachable && address_taken.contains(&block.start) {
+                        block.reachable = true;
+                        queue.push_back(j);
+                    }
+                }
+            }
+            if queue.is_empty() {
+                break;
+            }
+        }
+        Cfg { blocks }
+    }
+
+    /// The block holding an address.
+    pub fn block_at(&self, address: usize) -> Option<&BasicBlock> {
+        let i = self.blocks.partition_point(|b| b.start <= address).checked_sub(1)?;
+        self.blocks.get(i).filter(|b| address < b.end)
+    }
+
+    /// Jumps whose targets the analysis could not pin down, by block start.
+    pub fn unresolved(&self) -> impl Iterator<Item = &BasicBlock> {
+        self.blocks.iter().filter(|b| matches!(b.exit, Exit::Jump { unresolved: true, .. } | Exit::ProgramLoad))
+    }
+
+    /// Writes the graph in Graphviz DOT, one node per block holding its
+    /// disassembly. Unreachable blocks are dashed and grey, and jumps the
+    /// analysis could not resolve point at a `?` node.
+    pub fn write_dot(&self, words: &[u32], out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "digraph cfg {{")?;
+        writeln!(out, "    node [shape=box, fontname=\"monospace\"];")?;
+        for block in &self.blocks {
+            let mut label = String::new();
+            for (address, &word) in words.iter().enumerate().take(block.end).skip(block.start) {
+                let text = parser::decode(word).map_or_else(|_| format!(".word {:#010x}", word), |i| i.to_string());
+                label += &format!("{:08x}: {}\\l", address, text.replace('\\', "\\\\").replace('"', "\\\""));
+            }
+            let style = if block.reachable { "" } else { ", style=dashed, color=gray, fontcolor=gray" };
+            writeln!(out, "    b{} [label=\"{}\"{}];", block.start, label, style)?;
+        }
+        for block in &self.blocks {
+            for successor in &block.successors {
+                writeln!(out, "    b{} -> b{};", block.start, successor)?;
+            }
+        }
+        if self.unresolved().next().is_some() {
+            writeln!(out, "    unknown [label=\"?\", shape=circle];")?;
+            for block in self.unresolved() {
+                writeln!(out, "    b{} -> unknown [style=dashed];", block.start)?;
+            }
+        }
+        writeln!(out, "}}")
+    }
+}
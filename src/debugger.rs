@@ -0,0 +1,296 @@
+use std::io::{self, BufRead, Write};
+use std::sync::mpsc::Sender;
+use crate::parser::{self, MNEMONICS};
+use crate::um::{Status, UniversalMachine};
+
+const HELP: &str = "commands:
+  break PC | break MNEMONIC   stop before the instruction at PC, or before any such instruction
+  delete [N]                  remove breakpoint N, or all of them
+  breakpoints                 list the breakpoints
+  step [N]                    execute N instructions (default 1)
+  continue                    run until a breakpoint, a halt, a fault or a wait for input
+  regs                        print the program counter and the registers
+  x SEG OFF [COUNT]           print COUNT words of a segment (default 1)
+  list [N]                    disassemble N instructions either side of the PC (default 5)
+  set rN VALUE | set pc VALUE change a register or the program counter
+  poke SEG OFF VALUE          change a word of memory
+  segments                    list the mapped and unmapped segments
+  input TEXT                  queue TEXT and a newline as guest input
+  eof                         signal the end of guest input
+  quit
+an empty line repeats the last command; numbers may be written in hex with 0x";
+
+/// Where the debugger stops the guest.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Breakpoint {
+    /// Before the instruction at this address of segment 0 executes.
+    Address(usize),
+    /// Before any instruction with this opcode executes.
+    Opcode(u32),
+}
+
+/// An interactive debugger driving a machine one command at a time.
+pub struct Debugger {
+    pub um: UniversalMachine,
+    pub breakpoints: Vec<Breakpoint>,
+    // feeds the guest when its input device is a channel the debugger owns
+    input: Option<Sender<u8>>,
+    last_command: String,
+}
+
+fn parse_number(word: &str) -> Result<u64, String> {
+    let parsed = match word.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => word.parse(),
+    };
+    parsed.map_err(|_| format!("not a number: {}", word))
+}
+
+fn parse_word(word: &str) -> Result<u32, String> {
+    parse_number(word)?.try_into().map_err(|_| format!("does not fit in a word: {}", word))
+}
+
+fn argument<'a>(args: &[&'a str], n: usize) -> Result<&'a str, String> {
+    args.get(n).copied().ok_or_else(|| "missing argument; try help".to_string())
+}
+
+impl Debugger {
+    pub fn new(um: UniversalMachine) -> Self {
+        Self { um, breakpoints: vec![], input: None, last_command: String::new() }
+    }
+
+    /// Lets the `input` and `eof` commands feed a guest whose input device
+    /// is the receiving end of `sender`.
+    pub fn with_input(um: UniversalMachine, sender: Sender<u8>) -> Self {
+        Self { input: Some(sender), ..Self::new(um) }
+    }
+
+    /// Reads commands until `quit` or the end of `commands`.
+    pub fn repl(&mut self, commands: &mut impl BufRead, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "{}", self.location())?;
+        loop {
+            write!(out, "(rum) ")?;
+            out.flush()?;
+            let mut line = String::new();
+            if commands.read_line(&mut line)? == 0 || !self.command(&line, out)? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Carries out one command and reports whether the session goes on.
+    pub fn command(&mut self, line: &str, out: &mut impl Write) -> io::Result<bool> {
+        let line = match line.trim() {
+            "" => self.last_command.clone(),
+            line => line.to_string(),
+        };
+        self.last_command = line.clone();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        let Some((&name, args)) = words.split_first() else {
+            return Ok(true);
+        };
+        let result = match name {
+            "break" | "b" => self.add_breakpoint(args),
+            "delete" | "d" => self.delete_breakpoint(args),
+            "breakpoints" | "info" => Ok(self.list_breakpoints()),
+            "step" | "s" => match args.first() {
+                Some(n) => parse_number(n).map(|n| self.step(n)),
+                None => Ok(self.step(1)),
+            },
+            "continue" | "c" => Ok(self.step(u64::MAX)),
+            "regs" | "r" => Ok(self.registers()),
+            "x" => self.examine(args),
+            "list" | "l" => match args.first() {
+                Some(n) => parse_number(n).map(|n| self.disassemble(n as usize)),
+                None => Ok(self.disassemble(5)),
+            },
+            "set" => self.set(args),
+            "poke" => self.poke(args),
+            "segments" => Ok(self.segments()),
+            "input" => self.send_input(line.split_once(' ').map_or("", |(_, text)| text)),
+            "eof" => match self.input.take() {
+                Some(_) => Ok("end of input signaled".to_string()),
+                None => Err("guest input does not come from the debugger".to_string()),
+            },
+            "help" | "h" => Ok(HELP.to_string()),
+            "quit" | "q" => return Ok(false),
+            _ => Err(format!("unknown command: {}; try help", name)),
+        };
+        // guest output shows up before whatever the command reports
+        self.um.output.flush()?;
+        match result {
+            Ok(text) if text.is_empty() => {}
+            Ok(text) => writeln!(out, "{}", text)?,
+            Err(message) => writeln!(out, "error: {}", message)?,
+        }
+        Ok(true)
+    }
+
+    fn add_breakpoint(&mut self, args: &[&str]) -> Result<String, String> {
+        let target = argument(args, 0)?;
+        let breakpoint = match MNEMONICS.iter().position(|m| *m == target) {
+            Some(opcode) => Breakpoint::Opcode(opcode as u32),
+            None => Breakpoint::Address(parse_number(target)? as usize),
+        };
+        self.breakpoints.push(breakpoint);
+        Ok(format!("breakpoint {} at {}", self.breakpoints.len() - 1, describe(breakpoint)))
+    }
+
+    fn delete_breakpoint(&mut self, args: &[&str]) -> Result<String, String> {
+        match args.first() {
+            None => {
+                self.breakpoints.clear();
+                Ok("deleted every breakpoint".to_string())
+            }
+            Some(n) => {
+                let n = parse_number(n)? as usize;
+                if n >= self.breakpoints.len() {
+                    return Err(format!("no breakpoint {}", n));
+                }
+                self.breakpoints.remove(n);
+                Ok(format!("deleted breakpoint {}", n))
+            }
+        }
+    }
+
+    fn list_breakpoints(&self) -> String {
+        if self.breakpoints.is_empty() {
+            return "no breakpoints".to_string();
+        }
+        let lines: Vec<String> =
+            self.breakpoints.iter().enumerate().map(|(n, &b)| format!("{:>3}  {}", n, describe(b))).collect();
+        lines.join("\n")
+    }
+
+    fn at_breakpoint(&self) -> Option<usize> {
+        let pc = self.um.program_counter;
+        let word = self.um.segments.program().get(pc);
+        self.breakpoints.iter().position(|&b| match b {
+            Breakpoint::Address(address) => address == pc,
+            Breakpoint::Opcode(opcode) => word.is_some_and(|&word| parser::op(word) == opcode),
+        })
+    }
+
+    // Executes up to `n` instructions, stopping early at a breakpoint other
+    // than the one the guest starts on.
+    fn step(&mut self, n: u64) -> String {
+        for i in 0..n {
+            if i > 0 {
+                if let Some(b) = self.at_breakpoint() {
+                    return format!("breakpoint {}: {}", b, self.location());
+                }
+            }
+            match self.um.step() {
+                Ok(Status::Running) => {}
+                Ok(Status::Halted) => return "guest halted".to_string(),
+                Ok(Status::WaitingForInput) => {
+                    return format!("guest is waiting for input; use input or eof\n{}", self.location())
+                }
+                Ok(status) => return format!("guest stopped: {:?}", status),
+                Err(fault) => return format!("fault: {}", fault),
+            }
+        }
+        self.location()
+    }
+
+    // The next instruction to execute.
+    fn location(&self) -> String {
+        self.line(self.um.program_counter)
+    }
+
+    fn line(&self, pc: usize) -> String {
+        let marker = if pc == self.um.program_counter { "=>" } else { "  " };
+        let flag = if self.breakpoints.contains(&Breakpoint::Address(pc)) { '*' } else { ' ' };
+        match self.um.segments.program().get(pc) {
+            Some(&word) => {
+                let text = parser::decode(word).map_or_else(|_| "invalid".to_string(), |i| i.to_string());
+                format!("{}{}{:08x}: {:08x}  {}", marker, flag, pc, word, text)
+            }
+            None => format!("{}{}{:08x}: past the end of segment 0", marker, flag, pc),
+        }
+    }
+
+    fn registers(&self) -> String {
+        let mut text = format!("pc {:#010x}  status {:?}  {} instructions executed", self.um.program_counter, self.um.status(), self.um.instruction_count);
+        for (r, value) in self.um.registers.iter().enumerate() {
+            text += &format!("{}r{} {:#010x} {:>10}", if r % 4 == 0 { "\n" } else { "  " }, r, value, value);
+        }
+        text
+    }
+
+    fn examine(&self, args: &[&str]) -> Result<String, String> {
+        let segment = parse_word(argument(args, 0)?)?;
+        let offset = parse_word(argument(args, 1)?)?;
+        let count = match args.get(2) {
+            Some(count) => parse_number(count)?,
+            None => 1,
+        };
+        let words = self.um.segments.get(segment).ok_or_else(|| format!("segment {} is not mapped", segment))?;
+        let lines: Vec<String> = (offset as usize..words.len())
+            .take(count as usize)
+            .map(|i| format!("m[{}][{:#x}] = {:#010x} {:>10}", segment, i, words[i], words[i]))
+            .collect();
+        if lines.is_empty() {
+            return Err(format!("offset {} is past the end of segment {} ({} words)", offset, segment, words.len()));
+        }
+        Ok(lines.join("\n"))
+    }
+
+    fn disassemble(&self, around: usize) -> String {
+        let pc = self.um.program_counter;
+        let end = pc.saturating_add(around).saturating_add(1).min(self.um.segments.program().len()).max(pc + 1);
+        let lines: Vec<String> = (pc.saturating_sub(around)..end).map(|pc| self.line(pc)).collect();
+        lines.join("\n")
+    }
+
+    fn set(&mut self, args: &[&str]) -> Result<String, String> {
+        let target = argument(args, 0)?;
+        let value = parse_word(argument(args, 1)?)?;
+        if target == "pc" {
+            self.um.program_counter = value as usize;
+            // moving the program counter is how a halted or faulted guest gets going again
+            self.um.resume();
+            return Ok(self.location());
+        }
+        match target.strip_prefix('r').and_then(|r| r.parse::<usize>().ok()) {
+            Some(r) if r < 8 => {
+                self.um.registers[r] = value;
+                Ok(format!("r{} = {:#x}", r, value))
+            }
+            _ => Err(format!("no register {}", target)),
+        }
+    }
+
+    fn poke(&mut self, args: &[&str]) -> Result<String, String> {
+        let segment = parse_word(argument(args, 0)?)?;
+        let offset = parse_word(argument(args, 1)?)?;
+        let value = parse_word(argument(args, 2)?)?;
+        match self.um.segments.store(segment, offset, value) {
+            Ok(()) => Ok(format!("m[{}][{:#x}] = {:#x}", segment, offset, value)),
+            Err(e) => Err(e.to_string()),
+        }
+    }
+
+    fn segments(&self) -> String {
+        let segments = &self.um.segments;
+        let mapped: Vec<String> =
+            segments.mapped().map(|s| format!("{} ({} words)", s, segments.get(s).unwrap().len())).collect();
+        let unmapped: Vec<String> = segments.unmapped().map(|s| s.to_string()).collect();
+        format!("mapped: {}\nunmapped: {}", mapped.join(", "), if unmapped.is_empty() { "none".to_string() } else { unmapped.join(", ") })
+    }
+
+    fn send_input(&mut self, text: &str) -> Result<String, String> {
+        let sender = self.input.as_ref().ok_or("guest input does not come from the debugger")?;
+        for byte in text.bytes().chain(Some(b'\n')) {
+            sender.send(byte).map_err(|_| "the guest input is closed")?;
+        }
+        Ok(format!("queued {} bytes", text.len() + 1))
+    }
+}
+
+fn describe(breakpoint: Breakpoint) -> String {
+    match breakpoint {
+        Breakpoint::Address(pc) => format!("{:08x}", pc),
+        Breakpoint::Opcode(opcode) => format!("every {}", MNEMONICS[opcode as usize]),
+    }
+}
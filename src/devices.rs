@@ -0,0 +1,218 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Stdin, Stdout, Write};
+use std::path::Path;
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+
+/// Where the Input instruction gets its bytes from.
+pub trait InputDevice: Send {
+    /// Returns the next byte, or `None` once the end of input has been signaled.
+    /// A device with no byte ready yet returns an error of kind `WouldBlock`,
+    /// which makes the machine report `Status::WaitingForInput`.
+    fn read_byte(&mut self) -> io::Result<Option<u8>>;
+}
+
+/// Where the Output instruction sends its bytes.
+pub trait OutputDevice: Send {
+    fn write_byte(&mut self, byte: u8) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Reads a single byte from any reader, retrying on interrupts.
+fn read_one(reader: &mut impl Read) -> io::Result<Option<u8>> {
+    let mut buffer = [0; 1];
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(buffer[0])),
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Reads from the process's standard input through one shared buffer.
+pub struct StdinInput {
+    reader: BufReader<Stdin>,
+}
+
+impl StdinInput {
+    pub fn new() -> Self {
+        Self { reader: BufReader::new(io::stdin()) }
+    }
+}
+
+impl Default for StdinInput {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InputDevice for StdinInput {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        read_one(&mut self.reader)
+    }
+}
+
+/// Writes raw bytes to the process's standard output. Output is buffered
+/// until the machine's flush policy or the host flushes it.
+pub struct StdoutOutput {
+    writer: BufWriter<Stdout>,
+}
+
+impl StdoutOutput {
+    pub fn new() -> Self {
+        Self { writer: BufWriter::with_capacity(1 << 16, io::stdout()) }
+    }
+}
+
+impl Default for StdoutOutput {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl OutputDevice for StdoutOutput {
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.writer.write_all(&[byte])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Feeds the guest a fixed script of bytes, then signals end of input.
+pub struct BufferInput {
+    bytes: VecDeque<u8>,
+}
+
+impl BufferInput {
+    pub fn new(bytes: impl Into<Vec<u8>>) -> Self {
+        Self { bytes: bytes.into().into() }
+    }
+}
+
+impl InputDevice for BufferInput {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        Ok(self.bytes.pop_front())
+    }
+}
+
+/// Collects everything the guest writes. Clones share the same buffer, so
+/// keep one to read the output after handing the other to the machine. It
+/// is also a `Write`, for capturing traces and logs in memory.
+#[derive(Clone, Default)]
+pub struct BufferOutput {
+    bytes: Arc<Mutex<Vec<u8>>>,
+}
+
+impl BufferOutput {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A copy of everything written so far.
+    pub fn contents(&self) -> Vec<u8> {
+        self.bytes.lock().unwrap().clone()
+    }
+}
+
+impl Write for BufferOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.bytes.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl OutputDevice for BufferOutput {
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.bytes.lock().unwrap().push(byte);
+        Ok(())
+    }
+}
+
+/// Reads input from a file.
+pub struct FileInput {
+    reader: BufReader<File>,
+}
+
+impl FileInput {
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self { reader: BufReader::new(File::open(path)?) })
+    }
+}
+
+impl InputDevice for FileInput {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        read_one(&mut self.reader)
+    }
+}
+
+/// Writes output to a file, creating or truncating it.
+pub struct FileOutput {
+    writer: BufWriter<File>,
+}
+
+impl FileOutput {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self { writer: BufWriter::new(File::create(path)?) })
+    }
+}
+
+impl OutputDevice for FileOutput {
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.writer.write_all(&[byte])
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Takes input from another thread. An empty channel makes the machine wait
+/// for input, and dropping the sender signals end of input.
+pub struct ChannelInput {
+    receiver: Receiver<u8>,
+}
+
+impl ChannelInput {
+    pub fn new(receiver: Receiver<u8>) -> Self {
+        Self { receiver }
+    }
+}
+
+impl InputDevice for ChannelInput {
+    fn read_byte(&mut self) -> io::Result<Option<u8>> {
+        match self.receiver.try_recv() {
+            Ok(byte) => Ok(Some(byte)),
+            Err(TryRecvError::Empty) => Err(ErrorKind::WouldBlock.into()),
+            Err(TryRecvError::Disconnected) => Ok(None),
+        }
+    }
+}
+
+/// Sends output to another thread.
+pub struct ChannelOutput {
+    sender: Sender<u8>,
+}
+
+impl ChannelOutput {
+    pub fn new(sender: Sender<u8>) -> Self {
+        Self { sender }
+    }
+}
+
+impl OutputDevice for ChannelOutput {
+    fn write_byte(&mut self, byte: u8) -> io::Result<()> {
+        self.sender.send(byte).map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "output channel closed"))
+    }
+}
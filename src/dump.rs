@@ -0,0 +1,125 @@
+use std::io::{self, Write};
+use crate::parser::{self, Instruction};
+
+// Consecutive printable words it takes to call a stretch of memory a string.
+const MIN_STRING_RUN: usize = 4;
+
+fn printable(value: u32) -> Option<char> {
+    match value {
+        0x20..=0x7e => Some(value as u8 as char),
+        _ => None,
+    }
+}
+
+// Escapes a byte the way it would be written in a character constant.
+fn escape(value: u32) -> String {
+    match value {
+        0x0a => "\\n".to_string(),
+        0x09 => "\\t".to_string(),
+        0x22 => "\\\"".to_string(),
+        0x27 => "\\'".to_string(),
+        0x5c => "\\\\".to_string(),
+        _ => printable(value).map_or_else(|| format!("\\x{:02x}", value), String::from),
+    }
+}
+
+fn is_text(value: u32) -> bool {
+    printable(value).is_some() || value == 0x0a || value == 0x09
+}
+
+/// Why a word looks like data rather than code.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Annotation {
+    /// The word does not decode to an instruction.
+    Invalid,
+    /// The word starts a run of printable characters stored one per word.
+    String(String),
+    /// The word continues such a run.
+    InString,
+    /// The first LoadVal of a run loading printable characters, with the
+    /// text the run spells out.
+    Text(String),
+    /// A later LoadVal of such a run.
+    Character(u32),
+    /// The word decodes, but sets bits no instruction uses.
+    StrayBits,
+}
+
+// The character a LoadVal loads, if it is printable.
+fn loaded_text(word: u32) -> Option<u32> {
+    match parser::decode(word) {
+        Ok(Instruction::LoadVal { value, .. }) if is_text(value) => Some(value),
+        _ => None,
+    }
+}
+
+fn is_output(word: u32) -> bool {
+    matches!(parser::decode(word), Ok(Instruction::Output { .. }))
+}
+
+/// Guesses which words of a segment are data. Entry n annotates word n.
+pub fn annotate(words: &[u32]) -> Vec<Option<Annotation>> {
+    let mut notes: Vec<Option<Annotation>> = words.iter().map(|&word| annotate_word(word)).collect();
+    // runs of printable LoadVals, possibly each followed by the Output that prints it
+    let mut start = 0;
+    while start < words.len() {
+        let len = words[start..].iter().take_while(|&&word| loaded_text(word).is_some() || is_output(word)).count();
+        let chars: Vec<(usize, u32)> =
+            (start..start + len).filter_map(|i| loaded_text(words[i]).map(|value| (i, value))).collect();
+        if chars.len() >= MIN_STRING_RUN {
+            let text: String = chars.iter().map(|&(_, value)| escape(value)).collect();
+            notes[chars[0].0] = Some(Annotation::Text(text));
+            for &(i, value) in &chars[1..] {
+                notes[i] = Some(Annotation::Character(value));
+            }
+        }
+        start += len.max(1);
+    }
+    // runs of characters stored one per word
+    let mut start = 0;
+    while start < words.len() {
+        let len = words[start..].iter().take_while(|&&word| is_text(word)).count();
+        if len >= MIN_STRING_RUN {
+            let text: String = words[start..start + len].iter().map(|&word| escape(word)).collect();
+            notes[start] = Some(Annotation::String(text));
+            for note in &mut notes[start + 1..start + len] {
+                *note = Some(Annotation::InString);
+            }
+        }
+        start += len.max(1);
+    }
+    notes
+}
+
+fn annotate_word(word: u32) -> Option<Annotation> {
+    match parser::decode(word) {
+        Err(_) => Some(Annotation::Invalid),
+        // a word that does not survive a round trip had bits set that decoding dropped
+        Ok(instruction) if parser::encode(&instruction) != word => Some(Annotation::StrayBits),
+        Ok(_) => None,
+    }
+}
+
+/// Writes one line per word: address, hex word, then the instruction it
+/// decodes to. With `annotate`, words that look like data get a comment.
+pub fn dump(words: &[u32], annotate_data: bool, out: &mut impl Write) -> io::Result<()> {
+    let notes = if annotate_data { annotate(words) } else { vec![None; words.len()] };
+    for (address, (&word, note)) in words.iter().zip(notes).enumerate() {
+        let text = match parser::decode(word) {
+            Ok(instruction) => instruction.to_string(),
+            Err(_) => format!(".word {:#010x}", word),
+        };
+        let comment = match note {
+            None => String::new(),
+            Some(Annotation::Invalid) => "; data".to_string(),
+            Some(Annotation::String(text)) => format!("; data \"{}\"", text),
+            Some(Annotation::InString) => "; data".to_string(),
+            Some(Annotation::Text(text)) => format!("; \"{}\"", text),
+            Some(Annotation::Character(value)) => format!("; '{}'", escape(value)),
+            Some(Annotation::StrayBits) => "; data? unused bits set".to_string(),
+        };
+        let line = format!("{:08x}: {:08x}  {:<24} {}", address, word, text, comment);
+        writeln!(out, "{}", line.trim_end())?;
+    }
+    Ok(())
+}
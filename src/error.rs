@@ -0,0 +1,141 @@
+use std::fmt;
+use std::io;
+use crate::replay::JournalError;
+use crate::segments::{Quota, SegmentError};
+
+/// Machine state captured at the instruction that faulted.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Context {
+    /// Address in segment 0 of the faulting instruction.
+    pub pc: usize,
+    pub instruction: u32,
+    pub registers: [u32; 8],
+}
+
+impl Context {
+    pub fn new(pc: usize, instruction: u32, registers: &[u32; 8]) -> Self {
+        Self { pc, instruction, registers: *registers }
+    }
+}
+
+/// A fault raised by the guest program. Anything that is not one of these
+/// (a panic) is a bug in the emulator itself.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum UmError {
+    /// The program counter points past the end of segment 0.
+    ProgramCounterOutOfBounds { pc: usize, len: usize, registers: [u32; 8] },
+    /// The opcode field holds a value with no instruction (14 or 15).
+    InvalidOpcode(Context),
+    /// A load, store or LoadProg named a segment that is not mapped.
+    UnmappedSegment { context: Context, segment: u32 },
+    /// UnmapSeg named a segment that had already been unmapped.
+    DoubleUnmap { context: Context, segment: u32 },
+    /// UnmapSeg named segment 0.
+    UnmapSegmentZero(Context),
+    /// MapSeg or LoadProg would go over one of the memory quotas.
+    QuotaExceeded { context: Context, quota: Quota, limit: u64 },
+    /// A load or store addressed a word past the end of its segment.
+    OffsetOutOfBounds { context: Context, segment: u32, offset: u32, len: usize },
+    DivisionByZero(Context),
+    /// Output was asked to write a value that does not fit in a byte.
+    OutputOutOfRange { context: Context, value: u32 },
+    /// The input or output device reported an error.
+    Io { context: Context, kind: io::ErrorKind, message: String },
+    /// A replayed run read input or wrote output differently from its recording.
+    ReplayMismatch { context: Context, message: String },
+}
+
+impl UmError {
+    pub(crate) fn io(context: Context, error: io::Error) -> Self {
+        UmError::Io { context, kind: error.kind(), message: error.to_string() }
+    }
+
+    pub(crate) fn journal(context: Context, error: JournalError) -> Self {
+        match error {
+            JournalError::Io(e) => UmError::io(context, e),
+            JournalError::Mismatch(message) => UmError::ReplayMismatch { context, message },
+        }
+    }
+
+    pub(crate) fn segment(context: Context, error: SegmentError) -> Self {
+        match error {
+            SegmentError::Unmapped(segment) => UmError::UnmappedSegment { context, segment },
+            SegmentError::OutOfBounds { segment, offset, len } => {
+                UmError::OffsetOutOfBounds { context, segment, offset, len }
+            }
+            SegmentError::DoubleUnmap(segment) => UmError::DoubleUnmap { context, segment },
+            SegmentError::UnmapZero => UmError::UnmapSegmentZero(context),
+            SegmentError::QuotaExceeded { quota, limit } => UmError::QuotaExceeded { context, quota, limit },
+        }
+    }
+
+    /// The program counter of the faulting instruction.
+    pub fn pc(&self) -> usize {
+        match self {
+            UmError::ProgramCounterOutOfBounds { pc, .. } => *pc,
+            _ => self.context().unwrap().pc,
+        }
+    }
+
+    /// The instruction word, if the fault happened after one was fetched.
+    pub fn instruction(&self) -> Option<u32> {
+        self.context().map(|context| context.instruction)
+    }
+
+    pub fn registers(&self) -> &[u32; 8] {
+        match self {
+            UmError::ProgramCounterOutOfBounds { registers, .. } => registers,
+            _ => &self.context().unwrap().registers,
+        }
+    }
+
+    fn context(&self) -> Option<&Context> {
+        match self {
+            UmError::ProgramCounterOutOfBounds { .. } => None,
+            UmError::InvalidOpcode(context)
+            | UmError::DivisionByZero(context)
+            | UmError::UnmapSegmentZero(context)
+            | UmError::DoubleUnmap { context, .. }
+            | UmError::QuotaExceeded { context, .. }
+            | UmError::UnmappedSegment { context, .. }
+            | UmError::OffsetOutOfBounds { context, .. }
+            | UmError::OutputOutOfRange { context, .. }
+            | UmError::Io { context, .. }
+            | UmError::ReplayMismatch { context, .. } => Some(context),
+        }
+    }
+}
+
+impl fmt::Display for UmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UmError::ProgramCounterOutOfBounds { pc, len, .. } => {
+                write!(f, "program counter {} is past the end of segment 0 ({} words)", pc, len)?
+            }
+            UmError::InvalidOpcode(context) => {
+                write!(f, "invalid opcode {}", context.instruction >> 28)?
+            }
+            UmError::UnmappedSegment { segment, .. } => SegmentError::Unmapped(*segment).fmt(f)?,
+            UmError::DoubleUnmap { segment, .. } => SegmentError::DoubleUnmap(*segment).fmt(f)?,
+            UmError::UnmapSegmentZero(_) => SegmentError::UnmapZero.fmt(f)?,
+            UmError::QuotaExceeded { quota, limit, .. } => {
+                SegmentError::QuotaExceeded { quota: *quota, limit: *limit }.fmt(f)?
+            }
+            UmError::OffsetOutOfBounds { segment, offset, len, .. } => {
+                SegmentError::OutOfBounds { segment: *segment, offset: *offset, len: *len }.fmt(f)?
+            }
+            UmError::DivisionByZero(_) => write!(f, "division by zero")?,
+            UmError::OutputOutOfRange { value, .. } => {
+                write!(f, "output value {} is greater than 255", value)?
+            }
+            UmError::Io { message, .. } => write!(f, "I/O error: {}", message)?,
+            UmError::ReplayMismatch { message, .. } => write!(f, "replay diverged: {}", message)?,
+        }
+        if let Some(context) = self.context() {
+            write!(f, " at pc {} (instruction {:#010x})", context.pc, context.instruction)?;
+        }
+        write!(f, "; registers {:?}", self.registers())
+    }
+}
+
+impl std::error::Error for UmError {}
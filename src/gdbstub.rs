@@ -0,0 +1,343 @@
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use crate::error::UmError;
+use crate::um::{Status, UniversalMachine};
+
+// How many instructions a continue runs between checks for an interrupt.
+const INTERRUPT_CHECK_INTERVAL: u64 = 1 << 16;
+
+// Stop signals reported to the debugger.
+const SIGINT: u8 = 2;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+const SIGFPE: u8 = 8;
+const SIGSEGV: u8 = 11;
+
+/// Serves a machine over gdb's remote serial protocol.
+///
+/// This is a protocol stub, not support for a debugger front end. It
+/// answers the packets for registers, memory, software breakpoints, step,
+/// continue and the target description, and the tests drive it through
+/// those packets; no gdb or lldb session has been tested against it. gdb
+/// knows no Universal Machine architecture, so it lays the registers out,
+/// disassembles and reports the pc as whatever architecture it is set to:
+/// `info registers`, `x/i` and the like do not show the machine. Sending
+/// packets with `maint packet` does.
+///
+/// The registers are r0 to r7 followed by the program counter, each 32
+/// bits and big-endian. Memory addresses carry the segment in their upper
+/// 32 bits and a byte offset in the lower 32: the word at offset n of
+/// segment s lives at `s << 32 | n * 4`, most significant byte first. The
+/// program counter is reported as a byte address in segment 0, and
+/// breakpoints go on such addresses.
+pub struct GdbStub {
+    pub um: UniversalMachine,
+    /// Word offsets in segment 0 with a software breakpoint.
+    pub breakpoints: BTreeSet<usize>,
+    no_ack: bool,
+}
+
+// The registers as gdb's target description format lays them out. It has
+// no <architecture>, since gdb has none to name for the Universal Machine,
+// and gdb does not use it to lay out the registers.
+fn target_description() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\"><target version=\"1.0\"><feature name=\"org.rum.um\">",
+    );
+    for r in 0..8 {
+        xml += &format!("<reg name=\"r{}\" bitsize=\"32\" type=\"uint32\" regnum=\"{}\"/>", r, r);
+    }
+    xml += "<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"8\"/></feature></target>";
+    xml
+}
+
+fn hex_u32(value: u32) -> String {
+    format!("{:08x}", value)
+}
+
+fn parse_hex(text: &str) -> Option<u64> {
+    u64::from_str_radix(text, 16).ok()
+}
+
+fn decode_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0, |sum, byte| sum.wrapping_add(byte))
+}
+
+// Splits an address into its segment and byte offset.
+fn split_address(address: u64) -> (u32, u32) {
+    ((address >> 32) as u32, address as u32)
+}
+
+// The stop signal a fault maps to.
+fn fault_signal(fault: &UmError) -> u8 {
+    match fault {
+        UmError::InvalidOpcode(_) => SIGILL,
+        UmError::DivisionByZero(_) => SIGFPE,
+        _ => SIGSEGV,
+    }
+}
+
+impl GdbStub {
+    pub fn new(um: UniversalMachine) -> Self {
+        Self { um, breakpoints: BTreeSet::new(), no_ack: false }
+    }
+
+    /// Talks to one debugger until it detaches, kills the guest or hangs up.
+    pub fn serve(&mut self, stream: &mut TcpStream) -> io::Result<()> {
+        loop {
+            let packet = match self.read_packet(stream)? {
+                Some(packet) => packet,
+                None => return Ok(()),
+            };
+            match packet.chars().next() {
+                Some('D') => return self.send(stream, "OK"),
+                Some('k') => return Ok(()),
+                _ => {}
+            }
+            let mut interrupted = || interrupt_pending(stream);
+            let reply = self.respond(&packet, &mut interrupted);
+            self.um.output.flush()?;
+            self.send(stream, &reply)?;
+        }
+    }
+
+    // Reads the next packet, acknowledging it unless acks are off. Returns
+    // None once the connection closes.
+    fn read_packet(&mut self, stream: &mut TcpStream) -> io::Result<Option<String>> {
+        loop {
+            // acks, nacks and interrupts outside a packet need no answer here
+            match read_byte(stream)? {
+                None => return Ok(None),
+                Some(b'$') => {}
+                Some(_) => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                match read_byte(stream)? {
+                    None => return Ok(None),
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                }
+            }
+            let mut sum = [0; 2];
+            stream.read_exact(&mut sum)?;
+            let data = String::from_utf8_lossy(&data).into_owned();
+            let expected = std::str::from_utf8(&sum).ok().and_then(|sum| u8::from_str_radix(sum, 16).ok());
+            if self.no_ack {
+                return Ok(Some(data));
+            }
+            if expected == Some(checksum(&data)) {
+                stream.write_all(b"+")?;
+                return Ok(Some(data));
+            }
+            stream.write_all(b"-")?;
+        }
+    }
+
+    fn send(&mut self, stream: &mut TcpStream, data: &str) -> io::Result<()> {
+        write!(stream, "${}#{:02x}", data, checksum(data))?;
+        stream.flush()
+    }
+
+    /// The reply to one packet. `interrupted` is polled while the guest runs
+    /// and stops it when it returns true.
+    pub fn respond(&mut self, packet: &str, interrupted: &mut dyn FnMut() -> bool) -> String {
+        let Some(command) = packet.get(..1) else {
+            return String::new();
+        };
+        let rest = &packet[1..];
+        let reply = match command {
+            "?" => Some(format!("S{:02x}", SIGTRAP)),
+            "g" => Some(self.read_registers()),
+            "G" => self.write_registers(rest),
+            "p" => parse_hex(rest).and_then(|n| self.register(n as usize)).map(hex_u32),
+            "P" => rest.split_once('=').and_then(|(n, value)| self.write_register(n, value)),
+            "m" => self.read_memory(rest),
+            "M" => self.write_memory(rest),
+            "Z" | "z" => self.breakpoint(command == "Z", rest),
+            "s" => Some(self.resume(1, interrupted)),
+            "c" => Some(self.resume(u64::MAX, interrupted)),
+            "H" => Some("OK".to_string()),
+            "q" | "Q" => return self.query(packet),
+            // everything else is unsupported, which gdb expects to hear as an empty reply
+            _ => return String::new(),
+        };
+        reply.unwrap_or_else(|| "E01".to_string())
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return "PacketSize=4000;qXfer:features:read+;QStartNoAckMode+".to_string();
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let xml = target_description();
+            let Some((offset, len)) = range.split_once(',').and_then(|(o, l)| Some((parse_hex(o)?, parse_hex(l)?))) else {
+                return "E01".to_string();
+            };
+            let start = (offset as usize).min(xml.len());
+            let end = (start + len as usize).min(xml.len());
+            return format!("{}{}", if end == xml.len() { 'l' } else { 'm' }, &xml[start..end]);
+        }
+        match packet {
+            "QStartNoAckMode" => {
+                self.no_ack = true;
+                "OK".to_string()
+            }
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        }
+    }
+
+    fn register(&self, n: usize) -> Option<u32> {
+        match n {
+            0..=7 => Some(self.um.registers[n]),
+            8 => Some((self.um.program_counter as u32).wrapping_mul(4)),
+            _ => None,
+        }
+    }
+
+    fn set_register(&mut self, n: usize, value: u32) -> Option<()> {
+        match n {
+            0..=7 => self.um.registers[n] = value,
+            8 => self.um.program_counter = (value / 4) as usize,
+            _ => return None,
+        }
+        Some(())
+    }
+
+    fn read_registers(&self) -> String {
+        (0..9).map(|n| hex_u32(self.register(n).unwrap())).collect()
+    }
+
+    fn write_registers(&mut self, data: &str) -> Option<String> {
+        let bytes = decode_hex_bytes(data)?;
+        for (n, value) in bytes.chunks_exact(4).take(9).enumerate() {
+            self.set_register(n, u32::from_be_bytes(value.try_into().unwrap()))?;
+        }
+        Some("OK".to_string())
+    }
+
+    fn write_register(&mut self, n: &str, value: &str) -> Option<String> {
+        let bytes: [u8; 4] = decode_hex_bytes(value)?.try_into().ok()?;
+        self.set_register(parse_hex(n)? as usize, u32::from_be_bytes(bytes))?;
+        Some("OK".to_string())
+    }
+
+    fn read_byte(&self, address: u64) -> Option<u8> {
+        let (segment, byte) = split_address(address);
+        let word = self.um.segments.load(segment, byte / 4).ok()?;
+        Some(word.to_be_bytes()[(byte % 4) as usize])
+    }
+
+    fn write_byte(&mut self, address: u64, value: u8) -> Option<()> {
+        let (segment, byte) = split_address(address);
+        let mut bytes = self.um.segments.load(segment, byte / 4).ok()?.to_be_bytes();
+        bytes[(byte % 4) as usize] = value;
+        self.um.segments.store(segment, byte / 4, u32::from_be_bytes(bytes)).ok()
+    }
+
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let (address, len) = args.split_once(',')?;
+        let (address, len) = (parse_hex(address)?, parse_hex(len)?);
+        let mut hex = String::new();
+        for i in 0..len {
+            match self.read_byte(address.wrapping_add(i)) {
+                Some(byte) => hex += &format!("{:02x}", byte),
+                // a read that runs off the end returns what it could get
+                None if i > 0 => break,
+                None => return None,
+            }
+        }
+        Some(hex)
+    }
+
+    fn write_memory(&mut self, args: &str) -> Option<String> {
+        let (location, data) = args.split_once(':')?;
+        let (address, len) = location.split_once(',')?;
+        let (address, len) = (parse_hex(address)?, parse_hex(len)?);
+        let bytes = decode_hex_bytes(data)?;
+        if bytes.len() as u64 != len {
+            return None;
+        }
+        for (i, byte) in bytes.into_iter().enumerate() {
+            self.write_byte(address.wrapping_add(i as u64), byte)?;
+        }
+        Some("OK".to_string())
+    }
+
+    // Z0 and Z1 set software and hardware breakpoints, which are the same
+    // thing here; watchpoints are not supported.
+    fn breakpoint(&mut self, insert: bool, args: &str) -> Option<String> {
+        let mut fields = args.split(',');
+        let kind = fields.next()?;
+        if kind != "0" && kind != "1" {
+            return Some(String::new());
+        }
+        let (segment, byte) = split_address(parse_hex(fields.next()?)?);
+        if segment != 0 {
+            return None;
+        }
+        let pc = (byte / 4) as usize;
+        if insert {
+            self.breakpoints.insert(pc);
+        } else {
+            self.breakpoints.remove(&pc);
+        }
+        Some("OK".to_string())
+    }
+
+    // Runs up to `n` instructions and reports why the guest stopped.
+    fn resume(&mut self, n: u64, interrupted: &mut dyn FnMut() -> bool) -> String {
+        for i in 0..n {
+            if i > 0 {
+                if self.breakpoints.contains(&self.um.program_counter) {
+                    break;
+                }
+                if i % INTERRUPT_CHECK_INTERVAL == 0 && interrupted() {
+                    return format!("S{:02x}", SIGINT);
+                }
+            }
+            match self.um.step() {
+                Ok(Status::Running) => {}
+                Ok(Status::Halted) => return "W00".to_string(),
+                Ok(_) => break,
+                Err(fault) => return format!("S{:02x}", fault_signal(&fault)),
+            }
+        }
+        format!("S{:02x}", SIGTRAP)
+    }
+}
+
+fn read_byte(stream: &mut TcpStream) -> io::Result<Option<u8>> {
+    let mut byte = [0; 1];
+    match stream.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+// Whether the debugger sent an interrupt (a bare 0x03 byte) while the guest ran.
+fn interrupt_pending(stream: &mut TcpStream) -> bool {
+    let mut byte = [0; 1];
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let pending = match stream.peek(&mut byte) {
+        Ok(1) if byte[0] == 0x03 => stream.read(&mut byte).is_ok(),
+        Ok(_) => false,
+        Err(_) => false,
+    };
+    let _ = stream.set_nonblocking(false);
+    pending
+}
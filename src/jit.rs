@@ -0,0 +1,743 @@
+//! Compiles hot blocks of segment 0 into x86-64 machine code.
+//!
+//! A block is a straight run of instructions that only touch registers and
+//! mapped memory, optionally ended by a LoadProg. While a block runs, the
+//! eight UM registers live in host registers. Anything out of the ordinary
+//! (a fault, or a LoadProg that replaces the program) leaves the block just
+//! before the instruction responsible, and the interpreter carries on from
+//! there, so compiled code never has to report a fault itself. A store over
+//! a word some block was compiled from leaves the block just after it, and
+//! the blocks covering the word are thrown away before anything runs again.
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+compile_error!("the jit feature needs x86-64 Linux");
+
+use std::ffi::c_void;
+use std::mem::offset_of;
+use std::ptr::NonNull;
+use crate::blocks::{self, BlockIndex, Slot};
+use crate::parser::{self, Instruction};
+use crate::segments::{SegmentManager, View};
+use crate::um::UniversalMachine;
+
+// How many times execution has to reach an address before a block is
+// compiled there.
+const HOT_THRESHOLD: u32 = 16;
+
+// Host registers holding UM registers r0 to r7. The first six are
+// callee-saved; r10 and r11 are saved around calls into Rust.
+const PINNED: [u8; 8] = [RBX, RBP, R12, R13, R14, R15, R10, R11];
+
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+const RDX: u8 = 2;
+const RBX: u8 = 3;
+const RBP: u8 = 5;
+const RSI: u8 = 6;
+const RDI: u8 = 7;
+const R10: u8 = 10;
+const R11: u8 = 11;
+const R12: u8 = 12;
+const R13: u8 = 13;
+const R14: u8 = 14;
+const R15: u8 = 15;
+
+// Condition codes, as in the low nibble of a Jcc opcode.
+const BELOW: u8 = 0x2;
+const ABOVE_OR_EQUAL: u8 = 0x3;
+const EQUAL: u8 = 0x4;
+const NOT_EQUAL: u8 = 0x5;
+const ABOVE: u8 = 0x7;
+
+// What a helper returns when it could not do its job.
+const FAILED: u64 = 1 << 32;
+
+// What a store helper returns when the word it wrote into segment 0 may be
+// compiled code, so the block has to stop right after the store.
+const WROTE_CODE: u64 = 2;
+
+// Where a view keeps its length and whether it is writable.
+const VIEW_LEN: u8 = offset_of!(View, len) as u8;
+const VIEW_WRITABLE: u8 = offset_of!(View, writable) as u8;
+
+// What compiled code gets a pointer to. The registers are loaded on entry
+// and written back on exit.
+#[repr(C)]
+struct Context {
+    registers: [u32; 8],
+    segments: *mut SegmentManager,
+    // SegmentManager::views, and how many there are, for loads and stores
+    // to skip the helpers; kept up to date by the helpers that change them
+    views: *const View,
+    views_len: u64,
+    // the blocks, and their BlockIndex::coverage
+    index: *const BlockIndex<Block>,
+    coverage: *const u16,
+    // BlockIndex::entries, and how many there are
+    entries: *const Option<NonNull<u8>>,
+    len: u64,
+    // instructions executed so far, and how many may be
+    executed: u64,
+    budget: u64,
+    // the helpers, for compiled code to call through the context
+    load: extern "sysv64" fn(*mut Context, u32, u32) -> u64,
+    store: extern "sysv64" fn(*mut Context, u32, u32, u32) -> u64,
+    map: extern "sysv64" fn(*mut Context, u32) -> u64,
+    unmap: extern "sysv64" fn(*mut Context, u32) -> u64,
+}
+
+const REGISTERS: u8 = offset_of!(Context, registers) as u8;
+const VIEWS: u8 = offset_of!(Context, views) as u8;
+const VIEWS_LEN: u8 = offset_of!(Context, views_len) as u8;
+const ENTRIES: u8 = offset_of!(Context, entries) as u8;
+const LEN: u8 = offset_of!(Context, len) as u8;
+const EXECUTED: u8 = offset_of!(Context, executed) as u8;
+const BUDGET: u8 = offset_of!(Context, budget) as u8;
+// called through a 32-bit displacement, so these may lie anywhere
+const LOAD: u32 = offset_of!(Context, load) as u32;
+const STORE: u32 = offset_of!(Context, store) as u32;
+const MAP: u32 = offset_of!(Context, map) as u32;
+const UNMAP: u32 = offset_of!(Context, unmap) as u32;
+
+// The fields above that compiled code reaches through an 8-bit
+// displacement, which is signed: from 128 on it would point before the
+// context.
+const _: () = {
+    let disp8 = [
+        offset_of!(Context, registers) + 4 * 7,
+        offset_of!(Context, views),
+        offset_of!(Context, views_len),
+        offset_of!(Context, entries),
+        offset_of!(Context, len),
+        offset_of!(Context, executed),
+        offset_of!(Context, budget),
+    ];
+    let mut i = 0;
+    while i < disp8.len() {
+        assert!(disp8[i] < 128);
+        i += 1;
+    }
+};
+
+extern "sysv64" fn load(context: *mut Context, segment: u32, offset: u32) -> u64 {
+    // SAFETY: the machine is not touched while its block runs
+    let segments = unsafe { &*(*context).segments };
+    segments.load(segment, offset).map_or(FAILED, u64::from)
+}
+
+extern "sysv64" fn store(context: *mut Context, segment: u32, offset: u32, value: u32) -> u64 {
+    // SAFETY: as in load; coverage has a count for every word of segment 0
+    let (segments, index, coverage) =
+        unsafe { (&mut *(*context).segments, &*(*context).index, (*context).coverage) };
+    if segments.store(segment, offset, value).is_err() {
+        return FAILED;
+    }
+    if segment == 0 {
+        // the Jit drops stale blocks once the block returns
+        if unsafe { *coverage.add(offset as usize) } > 0 {
+            return WROTE_CODE;
+        }
+        // nothing compiled depends on the word, so the Jit need not hear of
+        // the store; it has seen every earlier one
+        index.skip_writes(segments);
+    }
+    0
+}
+
+extern "sysv64" fn map(context: *mut Context, len: u32) -> u64 {
+    // SAFETY: as in load
+    let (context, segments) = unsafe { (&mut *context, &mut *(*context).segments) };
+    let result = segments.map(len).map_or(FAILED, u64::from);
+    context.see_views(segments);
+    result
+}
+
+extern "sysv64" fn unmap(context: *mut Context, segment: u32) -> u64 {
+    // SAFETY: as in load
+    let segments = unsafe { &mut *(*context).segments };
+    segments.unmap(segment).map_or(FAILED, |_| 0)
+}
+
+impl Context {
+    fn see_views(&mut self, segments: &SegmentManager) {
+        self.views = segments.views().as_ptr();
+        self.views_len = segments.views().len() as u64;
+    }
+}
+
+extern "C" {
+    fn mmap(addr: *mut c_void, len: usize, prot: i32, flags: i32, fd: i32, offset: i64) -> *mut c_void;
+    fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+    fn munmap(addr: *mut c_void, len: usize) -> i32;
+}
+
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const PROT_EXEC: i32 = 4;
+const MAP_PRIVATE: i32 = 2;
+const MAP_ANONYMOUS: i32 = 0x20;
+const MAP_FAILED: *mut c_void = !0 as *mut c_void;
+
+// Bytes mapped at a time for compiled code.
+const CHUNK_SIZE: usize = 1 << 20;
+
+// Chunks kept before every block is thrown away to make room.
+const MAX_CHUNKS: usize = 64;
+
+// Executable memory that blocks are appended to. Keeping blocks next to each
+// other rather than on pages of their own spares the instruction TLB.
+#[derive(Default)]
+struct CodeArena {
+    chunks: Vec<*mut c_void>,
+    // bytes used in the last chunk
+    used: usize,
+}
+
+impl CodeArena {
+    // Copies code into the arena and returns where it starts, or None when
+    // the arena is full or the system refuses more memory.
+    fn add(&mut self, bytes: &[u8]) -> Option<*const u8> {
+        if self.chunks.is_empty() || self.used + bytes.len() > CHUNK_SIZE {
+            if self.chunks.len() == MAX_CHUNKS {
+                return None;
+            }
+            // SAFETY: asks for a fresh private mapping
+            let chunk = unsafe {
+                mmap(std::ptr::null_mut(), CHUNK_SIZE, PROT_READ | PROT_EXEC, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0)
+            };
+            if chunk == MAP_FAILED {
+                return None;
+            }
+            self.chunks.push(chunk);
+            self.used = 0;
+        }
+        let chunk = *self.chunks.last().unwrap();
+        // SAFETY: the chunk is only writable while the code is copied in, and
+        // the copy stays within it
+        unsafe {
+            if mprotect(chunk, CHUNK_SIZE, PROT_READ | PROT_WRITE) != 0 {
+                return None;
+            }
+            let entry = (chunk as *mut u8).add(self.used);
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), entry, bytes.len());
+            if mprotect(chunk, CHUNK_SIZE, PROT_READ | PROT_EXEC) != 0 {
+                return None;
+            }
+            // start every block on a cache line
+            self.used = (self.used + bytes.len()).next_multiple_of(64);
+            Some(entry)
+        }
+    }
+}
+
+impl Drop for CodeArena {
+    fn drop(&mut self) {
+        for &chunk in &self.chunks {
+            // SAFETY: the chunks came from mmap, and the blocks pointing into
+            // them go away with the arena
+            unsafe {
+                munmap(chunk, CHUNK_SIZE);
+            }
+        }
+    }
+}
+
+struct Block {
+    entry: *const u8,
+    // where other blocks jump in, with the registers already loaded
+    chained_entry: *const u8,
+    // most instructions one run of the block alone can execute
+    len: u64,
+}
+
+impl Block {
+    // Runs the block, and whatever blocks it jumps to, and returns the next
+    // program counter.
+    fn call(&self, context: &mut Context) -> u32 {
+        // SAFETY: the code was emitted by Emitter, which follows the System V
+        // calling convention, and the arena holding it outlives the block
+        unsafe {
+            let entry: extern "sysv64" fn(*mut Context) -> u32 = std::mem::transmute(self.entry);
+            entry(context)
+        }
+    }
+}
+
+impl blocks::Block for Block {
+    // the chained entry, null where there is no block, for blocks ending in
+    // a jump to look up their successor
+    type Entry = Option<NonNull<u8>>;
+
+    fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    fn entry(&self) -> Self::Entry {
+        NonNull::new(self.chained_entry as *mut u8)
+    }
+}
+
+/// Compiled blocks of segment 0, by starting address.
+#[derive(Default)]
+pub struct Jit {
+    index: BlockIndex<Block>,
+    arena: CodeArena,
+}
+
+// SAFETY: the pointers in a Jit all point into its own arena, which goes
+// wherever the Jit goes
+unsafe impl Send for Jit {}
+
+impl Jit {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs the block at the program counter if there is one, compiling it
+    /// first if the address has turned hot, then the blocks it jumps to, for
+    /// as long as none of them could run past `budget` instructions. Returns
+    /// how many instructions they executed, which is 0 when the interpreter
+    /// has to take the next one.
+    pub(crate) fn execute(&mut self, um: &mut UniversalMachine, budget: u64) -> u64 {
+        self.index.sync(&mut um.segments);
+        let pc = um.program_counter;
+        let Some(slot) = self.index.get_mut(pc) else {
+            return 0;
+        };
+        if let Slot::Cold(count) = slot {
+            *count += 1;
+            if *count < HOT_THRESHOLD {
+                return 0;
+            }
+            if self.arena.chunks.len() == MAX_CHUNKS {
+                // blocks thrown away still take up room, so start over
+                self.index.clear();
+                self.arena = CodeArena::default();
+                return 0;
+            }
+            let block = compile(um.segments.program(), pc, &self.index, &mut self.arena);
+            self.index.insert(pc, block);
+        }
+        let Some(Slot::Built(block)) = self.index.get(pc) else {
+            return 0;
+        };
+        let (coverage, entries) = (self.index.coverage(), self.index.entries());
+        let views = um.segments.views();
+        let mut context = Context {
+            registers: um.registers,
+            views: views.as_ptr(),
+            views_len: views.len() as u64,
+            segments: &mut um.segments,
+            index: &self.index,
+            coverage: coverage.as_ptr(),
+            entries: entries.as_ptr(),
+            len: entries.len() as u64,
+            executed: 0,
+            budget,
+            load,
+            store,
+            map,
+            unmap,
+        };
+        let pc = block.call(&mut context);
+        um.registers = context.registers;
+        um.program_counter = pc as usize;
+        um.instruction_count += context.executed;
+        context.executed
+    }
+}
+
+// Whether compiled code handles an instruction without leaving the block.
+fn compilable(instruction: &Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::CMov { .. }
+            | Instruction::SegLoad { .. }
+            | Instruction::SegStore { .. }
+            | Instruction::Add { .. }
+            | Instruction::Mul { .. }
+            | Instruction::Div { .. }
+            | Instruction::Nand { .. }
+            | Instruction::MapSeg { .. }
+            | Instruction::UnmapSeg { .. }
+            | Instruction::LoadVal { .. }
+    )
+}
+
+// Compiles the block at `start`. A block that runs into one already compiled
+// jumps to it rather than compiling the same instructions again.
+fn compile(program: &[u32], start: usize, index: &BlockIndex<Block>, arena: &mut CodeArena) -> Option<Block> {
+    let mut body = Vec::new();
+    let mut jump = None;
+    let mut joins = false;
+    for (address, &word) in program.iter().enumerate().skip(start).take(blocks::MAX_BLOCK) {
+        if address > start && index.is_built(address) {
+            joins = true;
+            break;
+        }
+        match parser::decode(word) {
+            Ok(instruction) if compilable(&instruction) => body.push(instruction),
+            Ok(Instruction::LoadProg { b, c }) => {
+                jump = Some((b, c));
+                break;
+            }
+            _ => break,
+        }
+    }
+    if body.is_empty() && jump.is_none() {
+        return None;
+    }
+
+    let len = body.len() as u64 + jump.is_some() as u64;
+    let mut e = Emitter::default();
+    e.prologue();
+    let chained = e.code.len();
+    e.check_budget(len as u32, start as u32);
+    for (i, instruction) in body.iter().enumerate() {
+        let pc = start + i;
+        let exit = (pc as u32, i as u32);
+        match *instruction {
+            Instruction::CMov { a, b, c } => {
+                e.rr(&[0x85], PINNED[c as usize], PINNED[c as usize]);
+                e.rr(&[0x0f, 0x45], PINNED[a as usize], PINNED[b as usize]);
+            }
+            Instruction::SegLoad { a, b, c } => {
+                let slow = e.view(b, c, false);
+                // mov $r[A], [rax + rcx * 4]
+                e.sib(0x8b, PINNED[a as usize]);
+                let done = e.jump_ahead(&[0xe9]);
+                slow.into_iter().for_each(|at| e.land(at));
+                e.call_helper(LOAD, &[b, c]);
+                e.exit_on_failure(exit);
+                e.rr(&[0x89], RAX, PINNED[a as usize]);
+                e.land(done);
+            }
+            Instruction::SegStore { a, b, c } => {
+                let slow = e.view(a, b, true);
+                // mov [rax + rcx * 4], $r[C]
+                e.sib(0x89, PINNED[c as usize]);
+                let done = e.jump_ahead(&[0xe9]);
+                slow.into_iter().for_each(|at| e.land(at));
+                e.call_helper(STORE, &[a, b, c]);
+                // cmp rax, WROTE_CODE
+                e.bytes(&[0x48, 0x83, 0xf8, WROTE_CODE as u8]);
+                e.exit_if(EQUAL, (pc as u32 + 1, i as u32 + 1));
+                e.rr(&[0x48, 0x85], RAX, RAX);
+                e.exit_if(NOT_EQUAL, exit);
+                e.land(done);
+            }
+            Instruction::Add { a, b, c } => e.alu(&[0x01], a, b, c),
+            Instruction::Mul { a, b, c } => {
+                e.rr(&[0x89], PINNED[b as usize], RAX);
+                e.rr(&[0x0f, 0xaf], RAX, PINNED[c as usize]);
+                e.rr(&[0x89], RAX, PINNED[a as usize]);
+            }
+            Instruction::Div { a, b, c } => {
+                e.rr(&[0x85], PINNED[c as usize], PINNED[c as usize]);
+                e.exit_if(EQUAL, exit);
+                e.rr(&[0x89], PINNED[b as usize], RAX);
+                e.rr(&[0x31], RDX, RDX);
+                e.rr(&[0xf7], 6, PINNED[c as usize]);
+                e.rr(&[0x89], RAX, PINNED[a as usize]);
+            }
+            Instruction::Nand { a, b, c } => {
+                e.rr(&[0x89], PINNED[b as usize], RAX);
+                e.rr(&[0x21], PINNED[c as usize], RAX);
+                e.rr(&[0xf7], 2, RAX);
+                e.rr(&[0x89], RAX, PINNED[a as usize]);
+            }
+            Instruction::MapSeg { b, c } => {
+                e.call_helper(MAP, &[c]);
+                e.exit_on_failure(exit);
+                e.rr(&[0x89], RAX, PINNED[b as usize]);
+            }
+            Instruction::UnmapSeg { c } => {
+                e.call_helper(UNMAP, &[c]);
+                e.exit_on_failure(exit);
+            }
+            Instruction::LoadVal { a, value } => e.mov_imm(PINNED[a as usize], value),
+            _ => unreachable!(),
+        }
+    }
+    let end = start + body.len();
+    match jump {
+        Some((b, c)) => {
+            // replacing segment 0 is the interpreter's job
+            e.rr(&[0x85], PINNED[b as usize], PINNED[b as usize]);
+            e.exit_if(NOT_EQUAL, (end as u32, body.len() as u32));
+            e.rr(&[0x89], PINNED[c as usize], RAX);
+            e.chain(body.len() as u32 + 1);
+        }
+        None if joins => {
+            e.mov_imm(RAX, end as u32);
+            e.chain(body.len() as u32);
+        }
+        None => e.exit((end as u32, body.len() as u32)),
+    }
+    e.cold_exits();
+    e.epilogue();
+    let entry = arena.add(&e.code)?;
+    // SAFETY: the chained entry lies within the code just added
+    let chained_entry = unsafe { entry.add(chained) };
+    Some(Block { entry, chained_entry, len })
+}
+
+// Writes x86-64 machine code. Register operands are host register numbers,
+// and operations are 32 bits wide unless an opcode carries REX.W.
+#[derive(Default)]
+struct Emitter {
+    code: Vec<u8>,
+    // where the jumps to the epilogue keep their 32-bit displacements
+    epilogue_jumps: Vec<usize>,
+    // conditional exits, emitted after the rest of the block so that the
+    // common path stays short: where each jump keeps its displacement, and
+    // the exit it leads to
+    cold: Vec<(usize, (u32, u32))>,
+}
+
+impl Emitter {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    // An instruction with a register-direct ModRM byte. A leading 0x48 in
+    // `opcode` is folded into the REX prefix.
+    fn rr(&mut self, opcode: &[u8], reg: u8, rm: u8) {
+        let (wide, opcode) = match opcode {
+            [0x48, rest @ ..] => (true, rest),
+            _ => (false, opcode),
+        };
+        let rex = (wide as u8) << 3 | (reg >> 3) << 2 | (rm >> 3);
+        if rex != 0 {
+            self.code.push(0x40 | rex);
+        }
+        self.bytes(opcode);
+        self.code.push(0xc0 | (reg & 7) << 3 | (rm & 7));
+    }
+
+    // mov r32, [rdi + disp8] when `load`, else mov [rdi + disp8], r32
+    fn rdi_relative(&mut self, load: bool, reg: u8, disp: u8) {
+        if reg >= 8 {
+            self.code.push(0x44);
+        }
+        self.code.push(if load { 0x8b } else { 0x89 });
+        self.code.push(0x40 | (reg & 7) << 3 | RDI);
+        self.code.push(disp);
+    }
+
+    fn push(&mut self, reg: u8) {
+        if reg >= 8 {
+            self.code.push(0x41);
+        }
+        self.code.push(0x50 + (reg & 7));
+    }
+
+    fn pop(&mut self, reg: u8) {
+        if reg >= 8 {
+            self.code.push(0x41);
+        }
+        self.code.push(0x58 + (reg & 7));
+    }
+
+    fn mov_imm(&mut self, reg: u8, value: u32) {
+        if reg >= 8 {
+            self.code.push(0x41);
+        }
+        self.code.push(0xb8 + (reg & 7));
+        self.bytes(&value.to_le_bytes());
+    }
+
+    // $r[A] := $r[B] op $r[C] for an op with an `op r/m32, r32` encoding,
+    // going through eax so that A may be the same register as C.
+    fn alu(&mut self, opcode: &[u8], a: u8, b: u8, c: u8) {
+        self.rr(&[0x89], PINNED[b as usize], RAX);
+        self.rr(opcode, PINNED[c as usize], RAX);
+        self.rr(&[0x89], RAX, PINNED[a as usize]);
+    }
+
+    // Saves the context pointer and the callee-saved registers, then loads
+    // the UM registers. The seven pushes leave the stack 16-byte aligned.
+    fn prologue(&mut self) {
+        for reg in [RBX, RBP, R12, R13, R14, R15, RDI] {
+            self.push(reg);
+        }
+        for (i, &reg) in PINNED.iter().enumerate() {
+            self.rdi_relative(true, reg, REGISTERS + 4 * i as u8);
+        }
+    }
+
+    // Adds the instructions executed, from the high half of rax, to the
+    // context, then writes back the UM registers and returns the program
+    // counter in the low half.
+    fn epilogue(&mut self) {
+        let here = self.code.len();
+        for &at in &self.epilogue_jumps {
+            let displacement = (here - (at + 4)) as u32;
+            self.code[at..at + 4].copy_from_slice(&displacement.to_le_bytes());
+        }
+        self.load_context();
+        // mov rcx, rax; shr rcx, 32; add [rdi + EXECUTED], rcx; mov eax, eax
+        self.rr(&[0x48, 0x89], RAX, RCX);
+        self.bytes(&[0x48, 0xc1, 0xe9, 0x20]);
+        self.bytes(&[0x48, 0x01, 0x4f, EXECUTED]);
+        self.rr(&[0x89], RAX, RAX);
+        for (i, &reg) in PINNED.iter().enumerate() {
+            self.rdi_relative(false, reg, REGISTERS + 4 * i as u8);
+        }
+        for reg in [RDI, R15, R14, R13, R12, RBP, RBX] {
+            self.pop(reg);
+        }
+        self.code.push(0xc3);
+    }
+
+    // mov rdi, [rsp], the context pointer saved by the prologue
+    fn load_context(&mut self) {
+        self.bytes(&[0x48, 0x8b, 0x3c, 0x24]);
+    }
+
+    // Leaves without executing anything unless `len` more instructions fit
+    // in the budget.
+    fn check_budget(&mut self, len: u32, pc: u32) {
+        self.load_context();
+        // mov rdx, [rdi + EXECUTED]; add rdx, len; cmp rdx, [rdi + BUDGET]
+        self.bytes(&[0x48, 0x8b, 0x57, EXECUTED]);
+        self.bytes(&[0x48, 0x81, 0xc2]);
+        self.bytes(&len.to_le_bytes());
+        self.bytes(&[0x48, 0x3b, 0x57, BUDGET]);
+        self.exit_if(ABOVE, (pc, 0));
+    }
+
+    // Having executed `executed` instructions, jumps to the address in eax:
+    // straight into the block there if one is compiled, or else back to
+    // the interpreter.
+    fn chain(&mut self, executed: u32) {
+        self.load_context();
+        // add qword [rdi + EXECUTED], executed
+        self.bytes(&[0x48, 0x81, 0x47, EXECUTED]);
+        self.bytes(&executed.to_le_bytes());
+        // cmp rax, [rdi + LEN]; jae epilogue
+        self.bytes(&[0x48, 0x3b, 0x47, LEN]);
+        self.bytes(&[0x0f, 0x83]);
+        self.epilogue_jump();
+        // mov rcx, [rdi + ENTRIES]; mov rcx, [rcx + rax * 8]; test rcx, rcx; jz epilogue
+        self.bytes(&[0x48, 0x8b, 0x4f, ENTRIES]);
+        self.bytes(&[0x48, 0x8b, 0x0c, 0xc1]);
+        self.rr(&[0x48, 0x85], RCX, RCX);
+        self.bytes(&[0x0f, 0x84]);
+        self.epilogue_jump();
+        // jmp rcx
+        self.bytes(&[0xff, 0xe1]);
+    }
+
+    // The displacement of a jump to the epilogue, filled in once it is emitted.
+    fn epilogue_jump(&mut self) {
+        self.epilogue_jumps.push(self.code.len());
+        self.bytes(&[0; 4]);
+    }
+
+    fn jump_to_epilogue(&mut self) {
+        self.code.push(0xe9);
+        self.epilogue_jump();
+    }
+
+    // Leaves the block with the program counter at `pc` after executing
+    // `executed` instructions.
+    fn exit(&mut self, (pc, executed): (u32, u32)) {
+        // mov rax, imm64
+        self.bytes(&[0x48, 0xb8]);
+        self.bytes(&((executed as u64) << 32 | pc as u64).to_le_bytes());
+        self.jump_to_epilogue();
+    }
+
+    // Exits when condition `cc` holds.
+    fn exit_if(&mut self, cc: u8, exit: (u32, u32)) {
+        self.bytes(&[0x0f, 0x80 | cc]);
+        self.cold.push((self.code.len(), exit));
+        self.bytes(&[0; 4]);
+    }
+
+    // Emits the exits the conditional jumps lead to.
+    fn cold_exits(&mut self) {
+        for (at, exit) in std::mem::take(&mut self.cold) {
+            let displacement = (self.code.len() - (at + 4)) as u32;
+            self.code[at..at + 4].copy_from_slice(&displacement.to_le_bytes());
+            self.exit(exit);
+        }
+    }
+
+    // Exits when the helper that just returned set bit 32 of rax.
+    fn exit_on_failure(&mut self, exit: (u32, u32)) {
+        // bt rax, 32
+        self.bytes(&[0x48, 0x0f, 0xba, 0xe0, 0x20]);
+        self.exit_if(BELOW, exit);
+    }
+
+    // Leaves rax pointing at word $r[OFFSET] of segment $r[SEGMENT], scaled
+    // by 4 into rcx, for a load, or a store when `store`. Returns the jumps
+    // to take instead, where the segment is not mapped, the offset is out of
+    // bounds, or a store has to go through the helper.
+    fn view(&mut self, segment: u8, offset: u8, store: bool) -> Vec<usize> {
+        let mut slow = vec![];
+        self.load_context();
+        self.rr(&[0x89], PINNED[segment as usize], RAX);
+        // cmp rax, [rdi + VIEWS_LEN]; jae slow
+        self.bytes(&[0x48, 0x3b, 0x47, VIEWS_LEN]);
+        slow.push(self.jump_ahead(&[0x0f, 0x80 | ABOVE_OR_EQUAL]));
+        // shl rax, 4; add rax, [rdi + VIEWS]
+        self.bytes(&[0x48, 0xc1, 0xe0, 4]);
+        self.bytes(&[0x48, 0x03, 0x47, VIEWS]);
+        if store {
+            // cmp dword [rax + VIEW_WRITABLE], 0; je slow
+            self.bytes(&[0x83, 0x78, VIEW_WRITABLE, 0]);
+            slow.push(self.jump_ahead(&[0x0f, 0x80 | EQUAL]));
+        }
+        self.rr(&[0x89], PINNED[offset as usize], RCX);
+        // cmp ecx, [rax + VIEW_LEN]; jae slow
+        self.bytes(&[0x3b, 0x48, VIEW_LEN]);
+        slow.push(self.jump_ahead(&[0x0f, 0x80 | ABOVE_OR_EQUAL]));
+        // mov rax, [rax]
+        self.bytes(&[0x48, 0x8b, 0x00]);
+        slow
+    }
+
+    // An instruction moving between `reg` and [rax + rcx * 4].
+    fn sib(&mut self, opcode: u8, reg: u8) {
+        if reg >= 8 {
+            self.code.push(0x44);
+        }
+        self.code.push(opcode);
+        self.code.push((reg & 7) << 3 | 0b100);
+        self.code.push(0b10_001_000);
+    }
+
+    // A jump whose 32-bit displacement `land` fills in once the code it
+    // leads to is emitted.
+    fn jump_ahead(&mut self, opcode: &[u8]) -> usize {
+        self.bytes(opcode);
+        let at = self.code.len();
+        self.bytes(&[0; 4]);
+        at
+    }
+
+    fn land(&mut self, at: usize) {
+        let displacement = (self.code.len() - (at + 4)) as u32;
+        self.code[at..at + 4].copy_from_slice(&displacement.to_le_bytes());
+    }
+
+    // Calls the helper at `helper` in the context with the context and the
+    // given UM registers as arguments, keeping r10 and r11 across the call.
+    fn call_helper(&mut self, helper: u32, registers: &[u8]) {
+        self.push(R10);
+        self.push(R11);
+        // mov rdi, [rsp + 16]
+        self.bytes(&[0x48, 0x8b, 0x7c, 0x24, 0x10]);
+        for (&r, argument) in registers.iter().zip([RSI, RDX, RCX]) {
+            self.rr(&[0x89], PINNED[r as usize], argument);
+        }
+        // call [rdi + helper], with a 32-bit displacement
+        self.bytes(&[0xff, 0x97]);
+        self.bytes(&helper.to_le_bytes());
+        self.pop(R11);
+        self.pop(R10);
+    }
+}
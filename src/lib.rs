@@ -1,5 +1,24 @@
 pub mod um;
 pub mod rumload;
 pub mod parser;
-// pub mod instructions;
-// pub mod tests;
+pub mod error;
+pub mod devices;
+pub mod segments;
+pub mod snapshot;
+pub mod replay;
+pub mod trace;
+pub mod profile;
+pub mod debugger;
+pub mod gdbstub;
+pub mod dump;
+pub mod asm;
+pub mod umasm;
+pub mod cfg;
+pub mod aot;
+#[cfg(feature = "jit")]
+mod blocks;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod instructions;
+#[cfg(test)]
+mod tests;
@@ -1,14 +1,622 @@
 use std::env;
-use rum::um::UniversalMachine;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::process;
+use std::net::TcpListener;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::{Duration, Instant};
+use rum::aot;
+use rum::asm;
+use rum::umasm;
+use rum::cfg::Cfg;
+use rum::debugger::Debugger;
+use rum::devices::{ChannelInput, FileInput, InputDevice};
+use rum::dump;
+use rum::error::UmError;
+use rum::gdbstub::GdbStub;
+use rum::replay::{Journal, Recorder, Replayer};
+use rum::segments::Quotas;
+use rum::snapshot::Snapshot;
+use rum::parser::{Fusion, MNEMONICS};
+use rum::profile::Profiler;
+use rum::trace::{TraceFilter, TraceFormat, Tracer};
+use rum::um::{EndOfInput, FlushPolicy, Status, UniversalMachine};
 use rum::rumload;
-use rum::parser;
+
+const USAGE: &str = "usage: rum [options] [program.um]
+       rum debug [options] program.um
+       rum gdb [--port=PORT] [options] program.um
+       rum dump [--annotate] program.um
+       rum dump [--annotate] --snapshot=FILE [--segment=N]
+       rum asm [--umasm] program.s [-o program.um]
+       rum cfg program.um [-o program.dot]
+       rum aot [--legacy-eof] program.um [-o program.c]
+
+  debug                     step through the program at an interactive prompt; guest
+                            input comes from the prompt's input command unless --input
+                            is given
+  gdb                       serve the program over gdb's remote serial protocol on
+                            127.0.0.1:PORT (default 1234). This answers the protocol's
+                            packets only and was never tested with a gdb or lldb
+                            session: gdb has no Universal Machine architecture, so
+                            info registers and disassembly do not show the machine.
+                            Send packets with maint packet instead: g returns r0 to
+                            r7 and then the pc times 4, 8 hex digits each; m and M
+                            read and write memory, big-endian, where segment s, word
+                            n sits at address s << 32 | n * 4; Z0 and z0 set and clear
+                            breakpoints at such addresses in segment 0
+  dump                      disassemble every word of the program, or of segment N
+                            (default 0) of a snapshot; --annotate marks words that
+                            look like data
+  asm                       assemble a program into a .um image, by default named
+                            after the source; --umasm, or a .ums source, selects the
+                            COMP40 umasm dialect
+  cfg                       write the control-flow graph of the program as Graphviz
+                            DOT, to stdout unless -o is given
+  aot                       translate the program into C, by default named after the
+                            program; build it with cc -O2 program.c, which takes
+                            minutes for a large program. This only pays off when the
+                            program runs the code it was loaded with: code it builds
+                            at run time is interpreted, so sandmark, for one, runs
+                            slower translated than under rum
+
+  --legacy-eof              load 1 instead of 0xFFFFFFFF into $r[C] at end of input
+  --no-jit                  interpret every instruction in a build with the jit feature
+  --flush=POLICY            when to flush output: never, halt, input (default), newline
+  --max-instructions=N      stop after executing N instructions
+  --time-limit=SECONDS      stop after running for SECONDS of wall-clock time
+  --max-memory-words=N      fault if mapped segments would hold more than N words in total
+  --max-segment-words=N     fault if a single segment would hold more than N words
+  --max-segments=N          fault if more than N segments would be mapped at once
+  --input=FILE              read guest input from FILE instead of stdin
+  --save-snapshot=FILE      save the machine to FILE if it stops without halting
+  --snapshot-interval=N     also save it every N instructions
+  --resume=FILE             start from a saved snapshot instead of a program; input
+                            already consumed is skipped when it comes from --input
+  --record=LOG              log every byte of input and output to LOG
+  --replay=LOG              feed the input recorded in LOG back to the guest and check
+                            that its output matches the recording byte for byte
+  --trace=FILE              write a trace of executed instructions to FILE
+  --trace-format=FORMAT     text (default) or binary
+  --trace-pc=A..B           only trace instructions at addresses A up to B
+  --trace-ops=OPS           only trace these mnemonics, separated by commas
+  --trace-window=A..B       only trace the Ath up to the Bth instruction executed
+                            (numbers may be written in hex with 0x)
+  --profile=FILE            write an execution profile of the guest to FILE as JSON
+                            and a summary of its hot spots to stderr
+  --profile-top=N           list the N most executed addresses in the summary (default 20)
+  --fuse                    have the interpreter run a few common sequences of
+                            instructions as one operation; implies --no-jit. This does
+                            not speed anything up: it is there for --fusion-stats, and
+                            runs midmark and sandmark no faster than the plain
+                            interpreter (see BENCHMARKS.md)
+  --fusion-stats            report on stderr how often the interpreter ran each sequence
+                            of instructions it fuses into one operation; implies --fuse,
+                            and rules out --trace and --profile";
+
+// Exit status when a limit stops the guest, as timeout(1) does.
+const LIMIT_EXIT: i32 = 124;
+
+// How many instructions run between checks of the wall clock.
+const TIME_CHECK_INTERVAL: u64 = 1 << 20;
+
+#[derive(PartialEq, Eq)]
+enum Command {
+    Run,
+    Debug,
+    Gdb,
+    Dump,
+    Asm,
+    Cfg,
+    Aot,
+}
+
+struct Options {
+    command: Command,
+    program: Option<String>,
+    port: u16,
+    annotate: bool,
+    snapshot: Option<String>,
+    segment: u32,
+    output: Option<String>,
+    umasm: bool,
+    end_of_input: EndOfInput,
+    no_jit: bool,
+    flush_policy: FlushPolicy,
+    max_instructions: Option<u64>,
+    time_limit: Option<Duration>,
+    quotas: Quotas,
+    input: Option<String>,
+    save_snapshot: Option<String>,
+    snapshot_interval: Option<u64>,
+    resume: Option<String>,
+    record: Option<String>,
+    replay: Option<String>,
+    trace: Option<String>,
+    trace_format: TraceFormat,
+    trace_filter: TraceFilter,
+    profile: Option<String>,
+    profile_top: usize,
+    fuse: bool,
+    fusion_stats: bool,
+}
+
+fn usage_error(message: String) -> ! {
+    eprintln!("{}\n{}", message, USAGE);
+    process::exit(2);
+}
+
+fn parse_number<T: std::str::FromStr>(option: &str, value: &str) -> T {
+    value
+        .parse()
+        .unwrap_or_else(|_| usage_error(format!("Invalid value for {}: {}", option, value)))
+}
+
+fn parse_u64(option: &str, value: &str) -> u64 {
+    let parsed = match value.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+    parsed.unwrap_or_else(|_| usage_error(format!("Invalid value for {}: {}", option, value)))
+}
+
+fn parse_range(option: &str, value: &str) -> Range<u64> {
+    match value.split_once("..") {
+        Some((start, end)) => parse_u64(option, start)..parse_u64(option, end),
+        None => usage_error(format!("Invalid range for {}: {}", option, value)),
+    }
+}
+
+// Turns a list of mnemonics into a mask with a bit per opcode.
+fn parse_opcodes(option: &str, value: &str) -> u16 {
+    value.split(',').fold(0, |mask, name| match MNEMONICS.iter().position(|m| *m == name) {
+        Some(opcode) => mask | 1 << opcode,
+        None => usage_error(format!("Unknown mnemonic for {}: {}", option, name)),
+    })
+}
+
+fn parse_args() -> Options {
+    let mut options = Options {
+        command: Command::Run,
+        program: None,
+        port: 1234,
+        annotate: false,
+        snapshot: None,
+        segment: 0,
+        output: None,
+        umasm: false,
+        end_of_input: EndOfInput::default(),
+        no_jit: false,
+        flush_policy: FlushPolicy::default(),
+        max_instructions: None,
+        time_limit: None,
+        quotas: Quotas::default(),
+        input: None,
+        save_snapshot: None,
+        snapshot_interval: None,
+        resume: None,
+        record: None,
+        replay: None,
+        trace: None,
+        trace_format: TraceFormat::Text,
+        trace_filter: TraceFilter::default(),
+        profile: None,
+        profile_top: 20,
+        fuse: false,
+        fusion_stats: false,
+    };
+    let mut args = env::args().skip(1).enumerate();
+    while let Some((i, arg)) = args.next() {
+        let (option, value) = match arg.split_once('=') {
+            Some((option, value)) if arg.starts_with("--") => (option, value),
+            _ => (arg.as_str(), ""),
+        };
+        match option {
+            "debug" if i == 0 => options.command = Command::Debug,
+            "gdb" if i == 0 => options.command = Command::Gdb,
+            "dump" if i == 0 => options.command = Command::Dump,
+            "asm" if i == 0 => options.command = Command::Asm,
+            "cfg" if i == 0 => options.command = Command::Cfg,
+            "aot" if i == 0 => options.command = Command::Aot,
+            "-o" => match args.next() {
+                Some((_, path)) => options.output = Some(path),
+                None => usage_error("-o needs a file name".to_string()),
+            },
+            "--port" => options.port = parse_number(option, value),
+            "--annotate" => options.annotate = true,
+            "--umasm" => options.umasm = true,
+            "--snapshot" => options.snapshot = Some(value.to_string()),
+            "--segment" => options.segment = parse_number(option, value),
+            "--legacy-eof" => options.end_of_input = EndOfInput::Legacy,
+            "--no-jit" => options.no_jit = true,
+            "--flush" => {
+                options.flush_policy = match value {
+                    "never" => FlushPolicy::Never,
+                    "halt" => FlushPolicy::AtHalt,
+                    "input" => FlushPolicy::BeforeInput,
+                    "newline" => FlushPolicy::OnNewline,
+                    _ => usage_error(format!("Invalid flush policy: {}", value)),
+                }
+            }
+            "--max-instructions" => options.max_instructions = Some(parse_number(option, value)),
+            "--time-limit" => {
+                // rejects negative, NaN and infinite limits, and ones too long for a Duration
+                let limit = Duration::try_from_secs_f64(parse_number(option, value));
+                let limit = limit.unwrap_or_else(|_| usage_error(format!("Invalid value for {}: {}", option, value)));
+                options.time_limit = Some(limit);
+            }
+            "--max-memory-words" => options.quotas.max_total_words = Some(parse_number(option, value)),
+            "--max-segment-words" => options.quotas.max_segment_words = Some(parse_number(option, value)),
+            "--max-segments" => options.quotas.max_segments = Some(parse_number(option, value)),
+            "--input" => options.input = Some(value.to_string()),
+            "--save-snapshot" => options.save_snapshot = Some(value.to_string()),
+            "--snapshot-interval" => options.snapshot_interval = Some(parse_number(option, value)),
+            "--resume" => options.resume = Some(value.to_string()),
+            "--record" => options.record = Some(value.to_string()),
+            "--replay" => options.replay = Some(value.to_string()),
+            "--trace" => options.trace = Some(value.to_string()),
+            "--trace-format" => {
+                options.trace_format = match value {
+                    "text" => TraceFormat::Text,
+                    "binary" => TraceFormat::Binary,
+                    _ => usage_error(format!("Invalid trace format: {}", value)),
+                }
+            }
+            "--trace-pc" => {
+                let range = parse_range(option, value);
+                options.trace_filter.pc_range = Some(range.start as usize..range.end as usize);
+            }
+            "--trace-ops" => options.trace_filter.opcodes = Some(parse_opcodes(option, value)),
+            "--trace-window" => options.trace_filter.window = Some(parse_range(option, value)),
+            "--profile" => options.profile = Some(value.to_string()),
+            "--profile-top" => options.profile_top = parse_number(option, value),
+            "--fuse" => options.fuse = true,
+            "--fusion-stats" => {
+                options.fuse = true;
+                options.fusion_stats = true;
+            }
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ if arg.starts_with("--") => usage_error(format!("Unknown option: {}", arg)),
+            _ => options.program = Some(arg),
+        }
+    }
+    if options.snapshot_interval.is_some() && options.save_snapshot.is_none() {
+        usage_error("--snapshot-interval needs --save-snapshot".to_string());
+    }
+    if options.record.is_some() && options.replay.is_some() {
+        usage_error("--record and --replay cannot be used together".to_string());
+    }
+    if options.snapshot_interval == Some(0) {
+        usage_error("--snapshot-interval must be at least 1".to_string());
+    }
+    // only the plain interpreter runs fused sequences
+    if options.fusion_stats && (options.trace.is_some() || options.profile.is_some()) {
+        usage_error("--fusion-stats cannot be used with --trace or --profile".to_string());
+    }
+    options
+}
+
+// Reports a failed flush, such as one into a pipe whose reader has gone
+// away, and exits.
+fn exit_on_write_error(what: &str, result: io::Result<()>) {
+    if let Err(e) = result {
+        eprintln!("Error: cannot write {}: {}", what, e);
+        process::exit(1);
+    }
+}
+
+fn save_snapshot(um: &UniversalMachine, path: &str) {
+    if let Err(e) = Snapshot::capture(um).save(path) {
+        eprintln!("Error: cannot save snapshot to {}: {}", path, e);
+    }
+}
+
+// Runs the guest until it stops or one of the limits is reached, saving
+// snapshots along the way if asked to.
+fn run(um: &mut UniversalMachine, options: &Options) -> Result<Status, UmError> {
+    let started = Instant::now();
+    loop {
+        let mut budget = u64::MAX;
+        if let Some(max) = options.max_instructions {
+            budget = max.saturating_sub(um.instruction_count);
+        }
+        if options.time_limit.is_some_and(|limit| started.elapsed() >= limit) {
+            budget = 0;
+        }
+        if budget == 0 {
+            return um.run_with_budget(0);
+        }
+        if options.time_limit.is_some() {
+            budget = budget.min(TIME_CHECK_INTERVAL);
+        }
+        if let Some(interval) = options.snapshot_interval {
+            budget = budget.min(interval - um.instruction_count % interval);
+        }
+        match um.run_with_budget(budget)? {
+            Status::OutOfFuel => {
+                if let (Some(path), Some(interval)) = (&options.save_snapshot, options.snapshot_interval) {
+                    if um.instruction_count.is_multiple_of(interval) {
+                        save_snapshot(um, path);
+                    }
+                }
+            }
+            status => return Ok(status),
+        }
+    }
+}
+
+fn write_profile(profiler: &Profiler, path: &str, top: usize) {
+    let written = File::create(path).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        profiler.write_json(&mut writer)?;
+        writer.flush()
+    });
+    if let Err(e) = written {
+        eprintln!("Error: cannot write profile to {}: {}", path, e);
+    }
+    exit_on_write_error("the profile summary", profiler.write_summary(&mut io::stderr().lock(), top));
+}
+
+fn write_fusion_stats(um: &UniversalMachine) {
+    let total = um.instruction_count.max(1) as f64;
+    eprintln!("{} instructions executed, fused sequences ran:", um.instruction_count);
+    for fusion in Fusion::ALL {
+        let count = um.fusions[fusion as usize];
+        let share = (count * fusion.instructions()) as f64 * 100.0 / total;
+        eprintln!("  {:<28} {:>14} {:>6.2}% of instructions", fusion.mnemonics(), count, share);
+    }
+}
+
+// Hands the machine to the interactive debugger until the user quits.
+fn debug(mut um: UniversalMachine, feed_input: bool) {
+    let mut debugger = if feed_input {
+        let (sender, receiver) = mpsc::channel();
+        um.input = Box::new(ChannelInput::new(receiver));
+        Debugger::with_input(um, sender)
+    } else {
+        Debugger::new(um)
+    };
+    if let Err(e) = debugger.repl(&mut io::stdin().lock(), &mut io::stdout()) {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+// Waits for a debugger to attach and serves the machine to it.
+fn serve_gdb(um: UniversalMachine, port: u16) {
+    let served = TcpListener::bind(("127.0.0.1", port)).and_then(|listener| {
+        eprintln!("Waiting for gdb on 127.0.0.1:{}", port);
+        let (mut stream, _) = listener.accept()?;
+        let mut stub = GdbStub::new(um);
+        stub.serve(&mut stream)?;
+        stub.um.output.flush()
+    });
+    if let Err(e) = served {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+// Disassembles the program, or a segment of a snapshot, to stdout.
+fn dump(options: &Options) {
+    let words = match &options.snapshot {
+        Some(path) => {
+            let snapshot = Snapshot::load(path).unwrap_or_else(|e| {
+                eprintln!("Error: cannot load snapshot {}: {}", path, e);
+                process::exit(1);
+            });
+            match snapshot.segments.get(options.segment as usize) {
+                Some(Some(words)) => words.clone(),
+                _ => {
+                    eprintln!("Error: segment {} is not mapped in {}", options.segment, path);
+                    process::exit(1);
+                }
+            }
+        }
+        None => rumload::load(options.program.as_deref()),
+    };
+    let mut out = BufWriter::new(io::stdout().lock());
+    // a closed pipe, as when piping into head, just ends the listing
+    let _ = dump::dump(&words, options.annotate, &mut out).and_then(|_| out.flush());
+}
+
+// Assembles the source named on the command line into a program image.
+fn assemble(options: &Options) {
+    let Some(source_path) = &options.program else {
+        usage_error("asm needs a source file".to_string());
+    };
+    let source = fs::read_to_string(source_path).unwrap_or_else(|e| {
+        eprintln!("Error: cannot read {}: {}", source_path, e);
+        process::exit(1);
+    });
+    let umasm = options.umasm || Path::new(source_path).extension().is_some_and(|e| e == "ums");
+    let assembled = if umasm { umasm::assemble(&source) } else { asm::assemble(&source) };
+    let words = assembled.unwrap_or_else(|e| {
+        eprintln!("{}:{}: {}", source_path, e.line, e.message);
+        process::exit(1);
+    });
+    let output = match &options.output {
+        Some(path) => PathBuf::from(path),
+        None => Path::new(source_path).with_extension("um"),
+    };
+    let written = File::create(&output).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        asm::write_image(&words, &mut writer)?;
+        writer.flush()
+    });
+    if let Err(e) = written {
+        eprintln!("Error: cannot write {}: {}", output.display(), e);
+        process::exit(1);
+    }
+}
+
+// Writes the program's control-flow graph and a summary of it.
+fn write_cfg(options: &Options) {
+    let words = rumload::load(options.program.as_deref());
+    let cfg = Cfg::build(&words);
+    let written = match &options.output {
+        Some(path) => File::create(path).and_then(|file| {
+            let mut writer = BufWriter::new(file);
+            cfg.write_dot(&words, &mut writer)?;
+            writer.flush()
+        }),
+        None => cfg.write_dot(&words, &mut BufWriter::new(io::stdout().lock())),
+    };
+    if let Err(e) = written {
+        eprintln!("Error: cannot write the graph: {}", e);
+        process::exit(1);
+    }
+    eprintln!(
+        "{} blocks, {} unreachable, {} with unresolved jumps",
+        cfg.blocks.len(),
+        cfg.blocks.iter().filter(|b| !b.reachable).count(),
+        cfg.unresolved().count()
+    );
+}
+
+// Translates the program into a C program.
+fn translate(options: &Options) {
+    let Some(program) = &options.program else {
+        usage_error("aot needs a program".to_string());
+    };
+    let words = rumload::load(Some(program));
+    let output = match &options.output {
+        Some(path) => PathBuf::from(path),
+        None => Path::new(program).with_extension("c"),
+    };
+    let written = File::create(&output).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        aot::translate(&words, options.end_of_input, &mut writer)?;
+        writer.flush()
+    });
+    if let Err(e) = written {
+        eprintln!("Error: cannot write {}: {}", output.display(), e);
+        process::exit(1);
+    }
+}
+
+fn open_input(path: &str, skip: u64) -> Box<dyn InputDevice> {
+    let mut input = FileInput::open(path).unwrap_or_else(|e| {
+        eprintln!("Error: cannot open {}: {}", path, e);
+        process::exit(1);
+    });
+    for _ in 0..skip {
+        if let Ok(None) | Err(_) = input.read_byte() {
+            break;
+        }
+    }
+    Box::new(input)
+}
 
 fn main() {
-    let input = env::args().nth(1);
+    let options = parse_args();
+    match options.command {
+        Command::Dump => return dump(&options),
+        Command::Asm => return assemble(&options),
+        Command::Cfg => return write_cfg(&options),
+        Command::Aot => return translate(&options),
+        _ => {}
+    }
     let mut um = UniversalMachine::new();
-    um.mem_segs[0] = rumload::load(input.as_deref());
+    um.end_of_input = options.end_of_input;
+    um.flush_policy = options.flush_policy;
+    um.segments.quotas = options.quotas;
+    if options.no_jit || options.fuse {
+        #[cfg(feature = "jit")]
+        {
+            um.jit = None;
+        }
+    }
+    match &options.resume {
+        Some(path) => {
+            let snapshot = Snapshot::load(path).unwrap_or_else(|e| {
+                eprintln!("Error: cannot load snapshot {}: {}", path, e);
+                process::exit(1);
+            });
+            snapshot.restore(&mut um);
+        }
+        None => um.segments.load_program(rumload::load(options.program.as_deref())),
+    }
+    um.segments.set_fusion(options.fuse);
+    if let Some(path) = &options.input {
+        um.input = open_input(path, um.input_consumed);
+    }
+    if let Some(path) = &options.record {
+        um.journal = Journal::Record(Recorder::create(path).unwrap_or_else(|e| {
+            eprintln!("Error: cannot create {}: {}", path, e);
+            process::exit(1);
+        }));
+    }
+    if let Some(path) = &options.trace {
+        let mut tracer = Tracer::create(path, options.trace_format).unwrap_or_else(|e| {
+            eprintln!("Error: cannot create {}: {}", path, e);
+            process::exit(1);
+        });
+        tracer.filter = options.trace_filter.clone();
+        um.tracer = Some(tracer);
+    }
+    if options.profile.is_some() {
+        um.profiler = Some(Profiler::new());
+    }
+    if let Some(path) = &options.replay {
+        um.journal = Journal::Replay(Replayer::open(path).unwrap_or_else(|e| {
+            eprintln!("Error: cannot read {}: {}", path, e);
+            process::exit(1);
+        }));
+    }
+    match options.command {
+        Command::Debug => return debug(um, options.input.is_none()),
+        Command::Gdb => return serve_gdb(um, options.port),
+        _ => {}
+    }
     // driver
-    loop {
-        parser::parse(&mut um);
-    } 
-}
\ No newline at end of file
+    let result = run(&mut um, &options);
+    exit_on_write_error("the guest's output", um.output.flush());
+    if let Some(tracer) = &mut um.tracer {
+        exit_on_write_error("the trace", tracer.flush());
+    }
+    if let (Some(path), Some(profiler)) = (&options.profile, &um.profiler) {
+        write_profile(profiler, path, options.profile_top);
+    }
+    if options.fusion_stats {
+        write_fusion_stats(&um);
+    }
+    match &mut um.journal {
+        Journal::Record(recorder) => exit_on_write_error("the recording", recorder.flush()),
+        Journal::Replay(replayer) if result == Ok(Status::Halted) => {
+            if replayer.remaining() > 0 {
+                eprintln!("Error: replay diverged: guest halted with {} recorded events left", replayer.remaining());
+                process::exit(1);
+            }
+            eprintln!("Replay matched the recording");
+        }
+        _ => {}
+    }
+    if let (Some(path), false) = (&options.save_snapshot, result == Ok(Status::Halted)) {
+        save_snapshot(&um, path);
+    }
+    match result {
+        Ok(Status::Halted) => {}
+        Ok(Status::OutOfFuel) => {
+            if options.max_instructions == Some(um.instruction_count) {
+                eprintln!("Error: instruction limit of {} reached", um.instruction_count);
+            } else {
+                eprintln!("Error: time limit reached after {} instructions", um.instruction_count);
+            }
+            process::exit(LIMIT_EXIT);
+        }
+        Ok(status) => {
+            eprintln!("Machine stopped: {:?}", status);
+            process::exit(1);
+        }
+        Err(fault) => {
+            eprintln!("Error: {}", fault);
+            process::exit(1);
+        }
+    }
+}
@@ -1,9 +1,12 @@
-use crate::um::UniversalMachine;
-//use crate::instructions;
+use crate::error::{Context, UmError};
+use crate::instructions;
+use crate::replay::{Event, Journal, JournalError};
+use crate::trace::TraceRecord;
+use crate::um::{FlushPolicy, Status, UniversalMachine};
 use num_traits::FromPrimitive;
 use num_derive::FromPrimitive;
-use std::io::*;
-use std::process;
+use std::fmt;
+use std::io::ErrorKind;
 
 // Code revised from rumdump by Professor Daniels.
 type Umi = u32;
@@ -12,6 +15,17 @@ pub struct Field {
     lsb: u32,
 }
 
+impl Field {
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// Position of the field's least significant bit in the word.
+    pub fn lsb(&self) -> u32 {
+        self.lsb
+    }
+}
+
 #[derive(Debug, PartialEq, Copy, Clone, FromPrimitive)]
 enum Opcode {
     CMov,
@@ -28,7 +42,6 @@ enum Opcode {
     Input,
     LoadProg,
     LoadVal,
-
 }
 pub static RA: Field = Field {width: 3, lsb: 6};
 pub static RB: Field = Field {width: 3, lsb: 3};
@@ -41,113 +54,670 @@ fn mask(bits: u32) -> u32 { (1 << bits) - 1 }
 pub fn get(field: &Field, instruction: &Umi) -> u32 {
     (instruction >> field.lsb) & mask(field.width)
 }
+/// Returns the instruction with `field` replaced by the low bits of `value`.
+pub fn set(field: &Field, instruction: &Umi, value: u32) -> Umi {
+    let field_mask = mask(field.width) << field.lsb;
+    (instruction & !field_mask) | ((value << field.lsb) & field_mask)
+}
 pub fn op(instruction: Umi) -> u32 {
     (instruction >> OP.lsb) & mask(OP.width)
 }
 
-pub fn parse(um: &mut UniversalMachine) {
-    let inst = um.mem_segs.get(0).unwrap().get(um.program_counter).unwrap();
+/// A single UM instruction with its operands. Register operands are register
+/// numbers (0-7), not register contents.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Instruction {
+    /// if $r[C] != 0 then $r[A] := $r[B]
+    CMov { a: u8, b: u8, c: u8 },
+    /// $r[A] := $m[$r[B]][$r[C]]
+    SegLoad { a: u8, b: u8, c: u8 },
+    /// $m[$r[A]][$r[B]] := $r[C]
+    SegStore { a: u8, b: u8, c: u8 },
+    /// $r[A] := ($r[B] + $r[C]) mod 2^32
+    Add { a: u8, b: u8, c: u8 },
+    /// $r[A] := ($r[B] * $r[C]) mod 2^32
+    Mul { a: u8, b: u8, c: u8 },
+    /// $r[A] := $r[B] div $r[C]
+    Div { a: u8, b: u8, c: u8 },
+    /// $r[A] := not ($r[B] and $r[C])
+    Nand { a: u8, b: u8, c: u8 },
+    Halt,
+    /// $r[B] := identifier of a new segment of $r[C] words
+    MapSeg { b: u8, c: u8 },
+    /// unmap $m[$r[C]]
+    UnmapSeg { c: u8 },
+    /// write $r[C] to the output device
+    Output { c: u8 },
+    /// $r[C] := next byte from the input device
+    Input { c: u8 },
+    /// $m[0] := copy of $m[$r[B]]; pc := $r[C]
+    LoadProg { b: u8, c: u8 },
+    /// $r[A] := value, a 25-bit constant
+    LoadVal { a: u8, value: u32 },
+}
+
+/// Assembly mnemonics, indexed by opcode.
+pub static MNEMONICS: [&str; 14] = [
+    "cmov", "load", "store", "add", "mul", "div", "nand",
+    "halt", "map", "unmap", "out", "in", "loadprog", "loadval",
+];
+
+impl Instruction {
+    /// The value of the instruction's opcode field.
+    pub fn opcode(&self) -> u32 {
+        op(encode(self))
+    }
+
+    pub fn mnemonic(&self) -> &'static str {
+        MNEMONICS[self.opcode() as usize]
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = self.mnemonic();
+        match *self {
+            Instruction::CMov { a, b, c }
+            | Instruction::SegLoad { a, b, c }
+            | Instruction::SegStore { a, b, c }
+            | Instruction::Add { a, b, c }
+            | Instruction::Mul { a, b, c }
+            | Instruction::Div { a, b, c }
+            | Instruction::Nand { a, b, c } => write!(f, "{} r{}, r{}, r{}", name, a, b, c),
+            Instruction::Halt => write!(f, "{}", name),
+            Instruction::MapSeg { b, c } | Instruction::LoadProg { b, c } => write!(f, "{} r{}, r{}", name, b, c),
+            Instruction::UnmapSeg { c } | Instruction::Output { c } | Instruction::Input { c } => {
+                write!(f, "{} r{}", name, c)
+            }
+            Instruction::LoadVal { a, value } => write!(f, "{} r{}, {}", name, a, value),
+        }
+    }
+}
+
+/// Why a word could not be decoded.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum DecodeError {
+    /// The opcode field holds 14 or 15.
+    InvalidOpcode(u32),
+}
+
+/// Decodes an instruction word. Fields an instruction does not use are ignored.
+pub fn decode(inst: Umi) -> Result<Instruction, DecodeError> {
+    let a = get(&RA, &inst) as u8;
+    let b = get(&RB, &inst) as u8;
+    let c = get(&RC, &inst) as u8;
+    let instruction = match FromPrimitive::from_u32(get(&OP, &inst)) {
+        Some(Opcode::CMov) => Instruction::CMov { a, b, c },
+        Some(Opcode::SegLoad) => Instruction::SegLoad { a, b, c },
+        Some(Opcode::SegStore) => Instruction::SegStore { a, b, c },
+        Some(Opcode::Add) => Instruction::Add { a, b, c },
+        Some(Opcode::Mul) => Instruction::Mul { a, b, c },
+        Some(Opcode::Div) => Instruction::Div { a, b, c },
+        Some(Opcode::Nand) => Instruction::Nand { a, b, c },
+        Some(Opcode::Halt) => Instruction::Halt,
+        Some(Opcode::MapSeg) => Instruction::MapSeg { b, c },
+        Some(Opcode::UnmapSeg) => Instruction::UnmapSeg { c },
+        Some(Opcode::Output) => Instruction::Output { c },
+        Some(Opcode::Input) => Instruction::Input { c },
+        Some(Opcode::LoadProg) => Instruction::LoadProg { b, c },
+        Some(Opcode::LoadVal) => Instruction::LoadVal {
+            a: get(&RL, &inst) as u8,
+            value: get(&VL, &inst),
+        },
+        None => return Err(DecodeError::InvalidOpcode(op(inst))),
+    };
+    Ok(instruction)
+}
+
+/// Encodes an instruction as a word, leaving unused fields zero. Operands
+/// too wide for their field are truncated to its low bits.
+pub fn encode(instruction: &Instruction) -> Umi {
+    // sets the three register fields of a word holding only the opcode
+    let abc = |opcode: Opcode, a: u8, b: u8, c: u8| {
+        let inst = set(&OP, &0, opcode as u32);
+        let inst = set(&RA, &inst, a as u32);
+        let inst = set(&RB, &inst, b as u32);
+        set(&RC, &inst, c as u32)
+    };
+    match *instruction {
+        Instruction::CMov { a, b, c } => abc(Opcode::CMov, a, b, c),
+        Instruction::SegLoad { a, b, c } => abc(Opcode::SegLoad, a, b, c),
+        Instruction::SegStore { a, b, c } => abc(Opcode::SegStore, a, b, c),
+        Instruction::Add { a, b, c } => abc(Opcode::Add, a, b, c),
+        Instruction::Mul { a, b, c } => abc(Opcode::Mul, a, b, c),
+        Instruction::Div { a, b, c } => abc(Opcode::Div, a, b, c),
+        Instruction::Nand { a, b, c } => abc(Opcode::Nand, a, b, c),
+        Instruction::Halt => abc(Opcode::Halt, 0, 0, 0),
+        Instruction::MapSeg { b, c } => abc(Opcode::MapSeg, 0, b, c),
+        Instruction::UnmapSeg { c } => abc(Opcode::UnmapSeg, 0, 0, c),
+        Instruction::Output { c } => abc(Opcode::Output, 0, 0, c),
+        Instruction::Input { c } => abc(Opcode::Input, 0, 0, c),
+        Instruction::LoadProg { b, c } => abc(Opcode::LoadProg, 0, b, c),
+        Instruction::LoadVal { a, value } => {
+            let inst = set(&OP, &0, Opcode::LoadVal as u32);
+            let inst = set(&RL, &inst, a as u32);
+            set(&VL, &inst, value)
+        }
+    }
+}
+
+/// An instruction with its fields already pulled out of the word. Segment 0
+/// is kept decoded this way so the fields are extracted once per load
+/// rather than once per execution.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub(crate) struct Decoded {
+    op: Op,
+    a: u8,
+    b: u8,
+    c: u8,
+    value: u32,
+}
+
+// What the interpreter runs for a word of segment 0: the word's own
+// instruction, named as in Opcode, or a fused sequence starting with it.
+// Either way a, b, c and value hold the word's own fields; a fused
+// sequence keeps the rest of what it needs in the fields its first
+// instruction leaves unused.
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum Op {
+    CMov,
+    SegLoad,
+    SegStore,
+    Add,
+    Mul,
+    Div,
+    Nand,
+    Halt,
+    MapSeg,
+    UnmapSeg,
+    Output,
+    Input,
+    LoadProg,
+    LoadVal,
+    // opcodes 14 and 15
+    Invalid,
+    // a word stored to since it was last predecoded
+    Stale,
+    // b and c hold the b and c of the loadprog; the second loadval is left
+    // as it is in the next word, since it needs all of value
+    Jump,
+    // value holds the a of the second nand
+    And,
+    // b holds the a of the add and c its other operand, the loaded register
+    // again when the add reads it twice
+    AddImmediate,
+}
+
+impl From<Option<Opcode>> for Op {
+    fn from(opcode: Option<Opcode>) -> Op {
+        match opcode {
+            Some(Opcode::CMov) => Op::CMov,
+            Some(Opcode::SegLoad) => Op::SegLoad,
+            Some(Opcode::SegStore) => Op::SegStore,
+            Some(Opcode::Add) => Op::Add,
+            Some(Opcode::Mul) => Op::Mul,
+            Some(Opcode::Div) => Op::Div,
+            Some(Opcode::Nand) => Op::Nand,
+            Some(Opcode::Halt) => Op::Halt,
+            Some(Opcode::MapSeg) => Op::MapSeg,
+            Some(Opcode::UnmapSeg) => Op::UnmapSeg,
+            Some(Opcode::Output) => Op::Output,
+            Some(Opcode::Input) => Op::Input,
+            Some(Opcode::LoadProg) => Op::LoadProg,
+            Some(Opcode::LoadVal) => Op::LoadVal,
+            None => Op::Invalid,
+        }
+    }
+}
+
+pub(crate) fn predecode(inst: Umi) -> Decoded {
+    let op = Op::from(FromPrimitive::from_u32(get(&OP, &inst)));
+    if op == Op::LoadVal {
+        return Decoded { op, a: get(&RL, &inst) as u8, b: 0, c: 0, value: get(&VL, &inst) };
+    }
+    Decoded {
+        op,
+        a: get(&RA, &inst) as u8,
+        b: get(&RB, &inst) as u8,
+        c: get(&RC, &inst) as u8,
+        value: 0,
+    }
+}
+
+impl Decoded {
+    const NOTHING: Decoded = Decoded { op: Op::Invalid, a: 0, b: 0, c: 0, value: 0 };
+
+    // The word on its own, as predecode left it.
+    fn unfused(self) -> Decoded {
+        match self.op {
+            Op::And => Decoded { op: Op::Nand, value: 0, ..self },
+            Op::Jump | Op::AddImmediate => Decoded { op: Op::LoadVal, b: 0, c: 0, ..self },
+            _ => self,
+        }
+    }
+}
+
+/// Sequences of instructions that compilers emit over and over, which the
+/// interpreter runs as one operation. Jumping into the middle of one runs
+/// the instructions from there on one at a time, as usual.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Fusion {
+    /// loadval, loadval, loadprog, jumping within segment 0 to one of the
+    /// values loaded
+    Jump,
+    /// nand t, b, c then nand a, t, t, which computes b and c
+    And,
+    /// loadval t, k then an add of t to a register
+    AddImmediate,
+}
+
+/// The most instructions a fused sequence holds.
+pub const MAX_FUSED: u64 = 3;
+
+impl Fusion {
+    pub const ALL: [Fusion; 3] = [Fusion::Jump, Fusion::And, Fusion::AddImmediate];
+
+    /// How many instructions the sequence holds.
+    pub fn instructions(self) -> u64 {
+        match self {
+            Fusion::Jump => 3,
+            Fusion::And | Fusion::AddImmediate => 2,
+        }
+    }
+
+    /// The mnemonics of the sequence.
+    pub fn mnemonics(self) -> &'static str {
+        match self {
+            Fusion::Jump => "loadval, loadval, loadprog",
+            Fusion::And => "nand, nand",
+            Fusion::AddImmediate => "loadval, add",
+        }
+    }
+}
+
+// The word at `pc` as the interpreter runs it: fused with the words after
+// it when they make up a sequence, and on its own otherwise.
+fn fused(decoded: &[Decoded], pc: usize) -> Decoded {
+    let first = decoded[pc].unfused();
+    let Some(second) = decoded.get(pc + 1).map(|d| d.unfused()) else {
+        return first;
+    };
+    let third = decoded.get(pc + 2).map(|d| d.unfused());
+    match (first.op, second.op) {
+        (Op::LoadVal, Op::LoadVal) => match third {
+            Some(third) if third.op == Op::LoadProg && (third.c == first.a || third.c == second.a) => {
+                Decoded { op: Op::Jump, b: third.b, c: third.c, ..first }
+            }
+            _ => first,
+        },
+        (Op::Nand, Op::Nand) if second.b == first.a && second.c == first.a => {
+            Decoded { op: Op::And, value: second.a as u32, ..first }
+        }
+        (Op::LoadVal, Op::Add) if second.b == first.a || second.c == first.a => {
+            let other = if second.b == first.a { second.c } else { second.b };
+            Decoded { op: Op::AddImmediate, b: second.a, c: other, ..first }
+        }
+        _ => first,
+    }
+}
+
+/// Fuses every sequence in a predecoded segment 0.
+pub(crate) fn fuse(decoded: &mut [Decoded]) {
+    for pc in 0..decoded.len() {
+        decoded[pc] = fused(decoded, pc);
+    }
+}
+
+/// Marks the word at `offset` in segment 0, which has just been stored
+/// to, for predecoding again before it runs. With fusion on, the two words
+/// before it are marked too if they can start a sequence, which may have
+/// ended or may now end at the stored word.
+#[inline]
+pub(crate) fn invalidate(decoded: &mut [Decoded], offset: usize, fusion: bool) {
+    decoded[offset].op = Op::Stale;
+    if fusion && offset > 0 {
+        if matches!(decoded[offset - 1].op, Op::Nand | Op::LoadVal | Op::And | Op::AddImmediate | Op::Jump) {
+            decoded[offset - 1].op = Op::Stale;
+        }
+        if offset > 1 && matches!(decoded[offset - 2].op, Op::LoadVal | Op::Jump) {
+            decoded[offset - 2].op = Op::Stale;
+        }
+    }
+}
+
+/// Predecodes afresh the words of segment 0 from `offset` on that
+/// `invalidate` marked, the last first, so that with fusion on a sequence a
+/// store completed is fused again before it runs.
+pub(crate) fn redecode(decoded: &mut [Decoded], program: &[Umi], offset: usize, fusion: bool) {
+    let mut end = offset + 1;
+    while end < decoded.len() && decoded[end].op == Op::Stale {
+        end += 1;
+    }
+    for pc in (offset..end).rev() {
+        decoded[pc] = predecode(program[pc]);
+        if fusion {
+            decoded[pc] = fused(decoded, pc);
+        }
+    }
+    if fusion {
+        for pc in offset.saturating_sub(2)..offset {
+            if decoded[pc].op != Op::Stale {
+                decoded[pc] = fused(decoded, pc);
+            }
+        }
+    }
+}
+
+/// Executes the instruction at the program counter and reports whether the
+/// machine can keep going. On a fault the program counter is left pointing
+/// at the faulting instruction.
+pub fn parse(um: &mut UniversalMachine) -> Result<Status, UmError> {
+    if um.tracer.is_some() || um.profiler.is_some() {
+        dispatch(um, instrumented)
+    } else {
+        dispatch(um, execute)
+    }
+}
+
+// parse for callers that already know the machine has no tracer or profiler attached
+#[inline(always)]
+pub(crate) fn parse_uninstrumented(um: &mut UniversalMachine) -> Result<Status, UmError> {
+    dispatch(um, execute)
+}
+
+/// Runs instructions for as long as the machine keeps running and the
+/// longest fused sequence fits in `budget`, taking what it runs off the
+/// budget, and returns how the machine was left. The program counter and
+/// the registers live in locals meanwhile. Fused sequences run as one
+/// operation, and the instructions that only touch registers and memory,
+/// jumps within segment 0 included, run in place; everything else, faults
+/// included, goes through `dispatch` one instruction at a time.
+pub(crate) fn run_fused(um: &mut UniversalMachine, budget: &mut u64) -> Result<Status, UmError> {
+    let mut pc = um.program_counter;
+    let mut registers = um.registers;
+    let mut left = *budget;
+    // the budget as it was when the instruction count was last brought up to date
+    let mut counted = left;
+    while left >= MAX_FUSED {
+        // past the end of segment 0 there is nothing to run here either
+        let inst = um.segments.decoded().get(pc).copied().unwrap_or(Decoded::NOTHING);
+        let (a, b, c) = (inst.a as usize & 7, inst.b as usize & 7, inst.c as usize & 7);
+        let ran = match inst.op {
+            // a jump within segment 0
+            Op::LoadProg if registers[b] == 0 => {
+                pc = registers[c] as usize;
+                left -= 1;
+                continue;
+            }
+            Op::Jump => {
+                // the marks keep the next two words a loadval and a loadprog
+                let second = um.segments.decoded()[pc + 1].unfused();
+                instructions::load_val(&mut registers, a, inst.value);
+                instructions::load_val(&mut registers, second.a as usize & 7, second.value);
+                // replacing segment 0 is left to dispatch
+                if registers[b] != 0 {
+                    pc += 2;
+                    left -= 2;
+                    continue;
+                }
+                pc = registers[c] as usize;
+                left -= 3;
+                um.fusions[Fusion::Jump as usize] += 1;
+                continue;
+            }
+            Op::And => {
+                instructions::nand(&mut registers, a, b, c);
+                instructions::nand(&mut registers, inst.value as usize & 7, a, a);
+                pc += 2;
+                left -= 2;
+                um.fusions[Fusion::And as usize] += 1;
+                continue;
+            }
+            Op::AddImmediate => {
+                instructions::load_val(&mut registers, a, inst.value);
+                instructions::add(&mut registers, b, c, a);
+                pc += 2;
+                left -= 2;
+                um.fusions[Fusion::AddImmediate as usize] += 1;
+                continue;
+            }
+            Op::Stale => {
+                um.segments.redecode(pc);
+                continue;
+            }
+            // faults leave the machine as it was, for dispatch to run the
+            // instruction again and report them
+            Op::CMov => {
+                instructions::cmov(&mut registers, a, b, c);
+                true
+            }
+            Op::SegLoad => instructions::seg_load(&mut registers, &um.segments, a, b, c).is_ok(),
+            Op::SegStore => instructions::seg_store(&registers, &mut um.segments, a, b, c).is_ok(),
+            Op::Add => {
+                instructions::add(&mut registers, a, b, c);
+                true
+            }
+            Op::Mul => {
+                instructions::mul(&mut registers, a, b, c);
+                true
+            }
+            Op::Div => instructions::div(&mut registers, a, b, c),
+            Op::Nand => {
+                instructions::nand(&mut registers, a, b, c);
+                true
+            }
+            Op::MapSeg => instructions::map_seg(&mut registers, &mut um.segments, b, c).is_ok(),
+            Op::UnmapSeg => instructions::unmap_seg(&registers, &mut um.segments, c).is_ok(),
+            Op::LoadVal => {
+                instructions::load_val(&mut registers, a, inst.value);
+                true
+            }
+            Op::Halt | Op::Output | Op::Input | Op::LoadProg | Op::Invalid => false,
+        };
+        if ran {
+            pc += 1;
+            left -= 1;
+            continue;
+        }
+        // a fault, or an instruction that does more than touch registers and
+        // memory: hand the machine its state back and let dispatch run it
+        um.program_counter = pc;
+        um.registers = registers;
+        um.instruction_count += counted - left;
+        let result = dispatch_out_of_line(um);
+        if result != Ok(Status::Running) {
+            *budget = left;
+            return result;
+        }
+        left -= 1;
+        counted = left;
+        pc = um.program_counter;
+        registers = um.registers;
+    }
+    um.program_counter = pc;
+    um.registers = registers;
+    um.instruction_count += counted - left;
+    *budget = left;
+    Ok(Status::Running)
+}
+
+// parse_uninstrumented for run_fused, which only needs it now and then and
+// runs faster without it inlined
+#[inline(never)]
+fn dispatch_out_of_line(um: &mut UniversalMachine) -> Result<Status, UmError> {
+    dispatch(um, execute)
+}
+
+#[inline(always)]
+fn dispatch(
+    um: &mut UniversalMachine,
+    execute: fn(&mut UniversalMachine, usize, Decoded) -> Result<Status, UmError>,
+) -> Result<Status, UmError> {
+    let pc = um.program_counter;
+    let inst = match um.segments.decoded().get(pc) {
+        Some(inst) if inst.op == Op::Stale => um.segments.redecode(pc),
+        Some(inst) => *inst,
+        None => {
+            return Err(UmError::ProgramCounterOutOfBounds {
+                pc,
+                len: um.segments.program().len(),
+                registers: um.registers,
+            })
+        }
+    };
     um.program_counter += 1;
-    let a_data = get(&RA, inst);
-    let b_data = get(&RB, inst);
-    let c_data = get(&RC, inst);
-
-    match FromPrimitive::from_u32(get(&OP, inst)) {
-        Some(Opcode::CMov) => {
-            //instructions::cmov(um, &a_data, &b_data, &c_data);
-            if um.registers[c_data as usize] != 0 {
-                um.registers[a_data as usize] = um.registers[b_data as usize];
-            }
-        }
-        Some(Opcode::SegLoad) => {
-            //instructions::seg_load(um, &a_data, &b_data, &c_data);
-            let r_b_data = um.registers[b_data as usize] as usize;
-            let r_c_data = um.registers[c_data as usize] as usize;
-            um.registers[a_data as usize] = um.mem_segs[r_b_data][r_c_data];
-        }
-        Some(Opcode::SegStore) => {
-            //instructions::seg_store(um, &a_data, &b_data, &c_data);
-            let r_a_data = um.registers[a_data as usize] as usize;
-            let r_b_data = um.registers[b_data as usize] as usize;
-            let r_c_data = um.registers[c_data as usize];
-            um.mem_segs[r_a_data][r_b_data] = r_c_data;
-        }
-        Some(Opcode::Add) => {
-            //instructions::add(um, &a_data, &b_data, &c_data);
-            um.registers[a_data as usize] = um.registers[b_data as usize].wrapping_add(um.registers[c_data as usize]);
-        }
-        Some(Opcode::Mul) => {
-            //instructions::mul(um, &a_data, &b_data, &c_data);
-            um.registers[a_data as usize] = um.registers[b_data as usize].wrapping_mul(um.registers[c_data as usize]);
-        }
-        Some(Opcode::Div) => {
-            //instructions::div(um, &a_data, &b_data, &c_data);
-            if um.registers[c_data as usize] == 0 {
-                //print to stderr
-                eprintln!("Error: division by zero");
-            }
-        
-            um.registers[a_data as usize] = um.registers[b_data as usize].wrapping_div(um.registers[c_data as usize]);
-        }
-        Some(Opcode::Nand) => {
-            //instructions::nand(um, &a_data, &b_data, &c_data);
-            um.registers[a_data as usize] = !(um.registers[b_data as usize] & um.registers[c_data as usize]);
-        }
-        Some(Opcode::Halt) => {
+    let result = execute(um, pc, inst);
+    match result {
+        // an Input still waiting for a byte has not executed yet
+        Ok(Status::WaitingForInput) => {}
+        Ok(_) => um.instruction_count += 1,
+        Err(_) => um.program_counter = pc,
+    }
+    result
+}
+
+// Executes an instruction and hands it to the machine's tracer and profiler.
+#[inline(never)]
+fn instrumented(um: &mut UniversalMachine, pc: usize, inst: Decoded) -> Result<Status, UmError> {
+    let count = um.instruction_count;
+    let word = um.segments.program()[pc];
+    let before = um.registers;
+    let result = execute(um, pc, inst);
+    if let Ok(Status::Running | Status::Halted) = result {
+        if let Some(profiler) = &mut um.profiler {
+            profiler.record(count, pc, word, &before, &um.segments);
+        }
+        if let Some(tracer) = &mut um.tracer {
+            let record = TraceRecord { count, pc, word, before, after: um.registers };
+            if let Err(e) = tracer.record(&record) {
+                return Err(UmError::io(Context::new(pc, word, &um.registers), e));
+            }
+        }
+    }
+    result
+}
+
+// Runs just the instruction, even when a fused sequence starts with it.
+#[inline(always)]
+fn execute(um: &mut UniversalMachine, pc: usize, inst: Decoded) -> Result<Status, UmError> {
+    let inst = inst.unfused();
+    let (a, b, c) = (inst.a as usize & 7, inst.b as usize & 7, inst.c as usize & 7);
+    let c_data = inst.c;
+    let b_data = inst.b;
+    // faults report the raw word, which is still in segment 0 since nothing faults after replacing it
+    let context = |um: &UniversalMachine| Context::new(pc, um.segments.program()[pc], &um.registers);
+
+    match inst.op {
+        Op::Halt => {
             //instructions::halt();
-            process::exit(0);
-        }
-        Some(Opcode::MapSeg) => {
-            //instructions::map_seg(um, &b_data, &c_data);
-            let r_c_data = um.registers[c_data as usize];
-            let new_segment = vec![0; r_c_data as usize];
-            // Check if we already have any unmapped mem_segs and if so reuse
-            if um.unmap_segs.len() > 0 {
-                let unmapped_seg_index = um.unmap_segs.pop().unwrap();
-                um.mem_segs[unmapped_seg_index as usize] = new_segment;
-                um.registers[b_data as usize] = unmapped_seg_index;
-            } else {
-                um.mem_segs.push(new_segment);
-                um.registers[b_data as usize] = (um.mem_segs.len() - 1) as u32;
-            }
-        }
-        Some(Opcode::UnmapSeg) => {
-            //instructions::unmap_seg(um, &c_data);
-            // tracker for unmapped segments
-            um.unmap_segs.push(um.registers[c_data as usize]);
-        }
-        Some(Opcode::Output) => {
+            if um.flush_policy >= FlushPolicy::AtHalt {
+                if let Err(e) = um.output.flush() {
+                    return Err(UmError::io(context(um), e));
+                }
+            }
+            return Ok(Status::Halted);
+        }
+        Op::Output => {
             //instructions::output(um, &c_data);
-            let out = u8::try_from(um.registers[c_data as usize]).unwrap();
-            print!("{}", out as char);
+            let value = um.registers[c_data as usize];
+            let out = match u8::try_from(value) {
+                Ok(out) => out,
+                Err(_) => return Err(UmError::OutputOutOfRange { context: context(um), value }),
+            };
+            let mut written = um.output.write_byte(out);
+            if out == b'\n' && um.flush_policy >= FlushPolicy::OnNewline {
+                written = written.and_then(|_| um.output.flush());
+            }
+            if let Err(e) = written {
+                return Err(UmError::io(context(um), e));
+            }
+            let journaled = match &mut um.journal {
+                Journal::Off => Ok(()),
+                Journal::Record(recorder) => recorder.record(Event::Output { byte: out }).map_err(JournalError::Io),
+                Journal::Replay(replayer) => replayer.output(out),
+            };
+            if let Err(e) = journaled {
+                return Err(UmError::journal(context(um), e));
+            }
         }
-        Some(Opcode::Input) => {
+        Op::Input => {
             //instructions::input(um, &c_data);
-            let value = stdin().bytes().next();
-            if let Some(byte) = value {
-                um.registers[c_data as usize] = byte.unwrap() as u32;
-            } else {
-                um.registers[c_data as usize] = 1 as u32;
+            if um.flush_policy >= FlushPolicy::BeforeInput {
+                if let Err(e) = um.output.flush() {
+                    return Err(UmError::io(context(um), e));
+                }
+            }
+            let read = match &mut um.journal {
+                Journal::Replay(replayer) => replayer.input(um.instruction_count),
+                _ => um.input.read_byte().map_err(JournalError::Io),
+            };
+            let byte = match read {
+                Ok(byte) => byte,
+                Err(JournalError::Io(e)) if e.kind() == ErrorKind::WouldBlock => {
+                    // nothing to read yet, so retry this instruction on the next step
+                    um.program_counter -= 1;
+                    return Ok(Status::WaitingForInput);
+                }
+                Err(e) => return Err(UmError::journal(context(um), e)),
+            };
+            if let Journal::Record(recorder) = &mut um.journal {
+                // flushed right away so the log survives a crash
+                let recorded = recorder.record(Event::Input { at: um.instruction_count, byte });
+                if let Err(e) = recorded.and_then(|_| recorder.flush()) {
+                    return Err(UmError::io(context(um), e));
+                }
+            }
+            match byte {
+                Some(byte) => {
+                    um.registers[c_data as usize] = byte as u32;
+                    um.input_consumed += 1;
+                }
+                None => um.registers[c_data as usize] = um.end_of_input.value(),
             }
         }
-        Some(Opcode::LoadProg) => {
+        Op::LoadProg => {
             //instructions::load_prog(um, &b_data, &c_data);
-            if um.registers[b_data as usize] != 0 {
-                let src_seg = um.mem_segs[um.registers[b_data as usize] as usize].clone();
-                let dst_seg = um.mem_segs.get_mut(0).unwrap();
-                *dst_seg = src_seg; 
+            let r_b_data = um.registers[b_data as usize];
+            if let Err(e) = um.segments.replace_program(r_b_data) {
+                return Err(UmError::segment(context(um), e));
             }
             um.program_counter = um.registers[c_data as usize] as usize;
         }
-        Some(Opcode::LoadVal) => {
-            //instructions::load_val(um, *inst);
-            let index = get(&RL, inst);
-            let value = get(&VL, inst);
-
-            um.registers[index as usize] = value;
+        Op::Invalid => {
+            return Err(UmError::InvalidOpcode(context(um)));
         }
-        None => {
-            panic!("Invalid opcode: {}", op(*inst));
+        Op::CMov => instructions::cmov(&mut um.registers, a, b, c),
+        Op::SegLoad => {
+            if let Err(e) = instructions::seg_load(&mut um.registers, &um.segments, a, b, c) {
+                return Err(UmError::segment(context(um), e));
+            }
         }
+        Op::SegStore => {
+            if let Err(e) = instructions::seg_store(&um.registers, &mut um.segments, a, b, c) {
+                return Err(UmError::segment(context(um), e));
+            }
+        }
+        Op::Add => instructions::add(&mut um.registers, a, b, c),
+        Op::Mul => instructions::mul(&mut um.registers, a, b, c),
+        Op::Div => {
+            if !instructions::div(&mut um.registers, a, b, c) {
+                return Err(UmError::DivisionByZero(context(um)));
+            }
+        }
+        Op::Nand => instructions::nand(&mut um.registers, a, b, c),
+        Op::MapSeg => {
+            if let Err(e) = instructions::map_seg(&mut um.registers, &mut um.segments, b, c) {
+                return Err(UmError::segment(context(um), e));
+            }
+        }
+        Op::UnmapSeg => {
+            if let Err(e) = instructions::unmap_seg(&um.registers, &mut um.segments, c) {
+                return Err(UmError::segment(context(um), e));
+            }
+        }
+        Op::LoadVal => instructions::load_val(&mut um.registers, a, inst.value),
+        // taken apart above
+        Op::Stale | Op::Jump | Op::And | Op::AddImmediate => {}
     }
-}
\ No newline at end of file
+    Ok(Status::Running)
+}
@@ -0,0 +1,173 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt::Write as _;
+use std::io::{self, Write};
+use crate::parser::{self, Instruction, MNEMONICS};
+use crate::segments::SegmentManager;
+
+/// Counts where the guest spends its time. Attach one to a machine's
+/// `profiler` field to profile it.
+pub struct Profiler {
+    /// Executions per opcode.
+    pub opcodes: [u64; 14],
+    /// Executions per segment 0 address.
+    pub pcs: Vec<u64>,
+    // the word last executed at each address, for disassembly
+    words: Vec<u32>,
+    /// How often each LoadProg target was jumped to.
+    pub jump_targets: HashMap<u32, u64>,
+    /// LoadProg instructions that replaced segment 0.
+    pub program_loads: u64,
+    /// MapSeg sizes in power-of-two buckets: bucket 0 counts empty
+    /// segments and bucket n counts sizes from 2^(n-1) to 2^n - 1.
+    pub map_sizes: [u64; 33],
+    /// (instructions executed, live segments, live words), sampled every
+    /// `sample_interval` instructions.
+    pub live_segments: Vec<(u64, u64, u64)>,
+    pub max_live_segments: u64,
+    pub sample_interval: u64,
+    instructions: u64,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Self {
+            opcodes: [0; 14],
+            pcs: vec![],
+            words: vec![],
+            jump_targets: HashMap::new(),
+            program_loads: 0,
+            map_sizes: [0; 33],
+            live_segments: vec![],
+            max_live_segments: 0,
+            sample_interval: 1 << 20,
+            instructions: 0,
+        }
+    }
+
+    /// Counts one executed instruction; `before` holds the registers as
+    /// they were when it started.
+    pub fn record(&mut self, count: u64, pc: usize, word: u32, before: &[u32; 8], segments: &SegmentManager) {
+        self.instructions += 1;
+        self.opcodes[parser::op(word) as usize] += 1;
+        if pc >= self.pcs.len() {
+            self.pcs.resize(pc + 1, 0);
+            self.words.resize(pc + 1, 0);
+        }
+        self.pcs[pc] += 1;
+        self.words[pc] = word;
+        match parser::decode(word) {
+            Ok(Instruction::LoadProg { b, c }) => {
+                *self.jump_targets.entry(before[c as usize]).or_insert(0) += 1;
+                if before[b as usize] != 0 {
+                    self.program_loads += 1;
+                }
+            }
+            Ok(Instruction::MapSeg { c, .. }) => {
+                self.map_sizes[(32 - before[c as usize].leading_zeros()) as usize] += 1;
+                self.max_live_segments = self.max_live_segments.max(segments.live_segments());
+            }
+            _ => {}
+        }
+        if count.is_multiple_of(self.sample_interval) {
+            self.live_segments.push((count, segments.live_segments(), segments.live_words()));
+        }
+    }
+
+    /// The `n` most executed addresses, most executed first.
+    pub fn hot_spots(&self, n: usize) -> Vec<(usize, u64)> {
+        let mut hot: Vec<(usize, u64)> = self.pcs.iter().copied().enumerate().filter(|&(_, count)| count > 0).collect();
+        hot.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        hot.truncate(n);
+        hot
+    }
+
+    fn disassemble(&self, pc: usize) -> String {
+        parser::decode(self.words[pc]).map_or_else(|_| "invalid".to_string(), |i| i.to_string())
+    }
+
+    /// Writes every count as a JSON object.
+    pub fn write_json(&self, writer: &mut impl Write) -> io::Result<()> {
+        let mut json = String::new();
+        let _ = write!(json, "{{\n  \"instructions\": {},\n  \"opcodes\": {{", self.instructions);
+        for (opcode, count) in self.opcodes.iter().enumerate() {
+            let _ = write!(json, "{}\"{}\": {}", if opcode == 0 { "" } else { ", " }, MNEMONICS[opcode], count);
+        }
+        json += "},\n  \"pcs\": [";
+        for (i, (pc, count)) in self.hot_spots(usize::MAX).into_iter().enumerate() {
+            let _ = write!(
+                json,
+                "{}\n    {{\"pc\": {}, \"count\": {}, \"word\": {}, \"disassembly\": \"{}\"}}",
+                if i == 0 { "" } else { "," },
+                pc,
+                count,
+                self.words[pc],
+                self.disassemble(pc)
+            );
+        }
+        json += "\n  ],\n  \"jump_targets\": [";
+        let mut targets: Vec<(&u32, &u64)> = self.jump_targets.iter().collect();
+        targets.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (i, (target, count)) in targets.into_iter().enumerate() {
+            let _ = write!(json, "{}\n    {{\"target\": {}, \"count\": {}}}", if i == 0 { "" } else { "," }, target, count);
+        }
+        let _ = write!(json, "\n  ],\n  \"program_loads\": {},\n  \"map_sizes\": [", self.program_loads);
+        let buckets = self.map_sizes.iter().enumerate().filter(|(_, &count)| count > 0);
+        for (i, (bucket, count)) in buckets.enumerate() {
+            let (min, max) = if bucket == 0 { (0, 0) } else { (1u64 << (bucket - 1), (1u64 << bucket) - 1) };
+            let _ = write!(
+                json,
+                "{}\n    {{\"min\": {}, \"max\": {}, \"count\": {}}}",
+                if i == 0 { "" } else { "," },
+                min,
+                max,
+                count
+            );
+        }
+        json += "\n  ],\n  \"live_segments\": [";
+        for (i, (count, segments, words)) in self.live_segments.iter().enumerate() {
+            let _ = write!(
+                json,
+                "{}\n    {{\"instruction\": {}, \"segments\": {}, \"words\": {}}}",
+                if i == 0 { "" } else { "," },
+                count,
+                segments,
+                words
+            );
+        }
+        let _ = writeln!(json, "\n  ],\n  \"max_live_segments\": {}\n}}", self.max_live_segments);
+        writer.write_all(json.as_bytes())
+    }
+
+    /// Writes the opcode histogram and the `n` hottest addresses with their
+    /// disassembly.
+    pub fn write_summary(&self, writer: &mut impl Write, n: usize) -> io::Result<()> {
+        let total = self.instructions.max(1) as f64;
+        writeln!(writer, "{} instructions executed", self.instructions)?;
+        let mut opcodes: Vec<(usize, u64)> = self.opcodes.iter().copied().enumerate().collect();
+        opcodes.sort_by_key(|&(_, count)| Reverse(count));
+        for (opcode, count) in opcodes.into_iter().filter(|&(_, count)| count > 0) {
+            writeln!(writer, "  {:<10} {:>14} {:>6.2}%", MNEMONICS[opcode], count, count as f64 * 100.0 / total)?;
+        }
+        writeln!(writer, "top {} addresses:", n)?;
+        for (pc, count) in self.hot_spots(n) {
+            writeln!(
+                writer,
+                "  {:08x}: {:08x}  {:<24} {:>14} {:>6.2}%",
+                pc,
+                self.words[pc],
+                self.disassemble(pc),
+                count,
+                count as f64 * 100.0 / total
+            )?;
+        }
+        writeln!(writer, "{} LoadProg jumps replaced segment 0", self.program_loads)?;
+        writeln!(writer, "at most {} segments live at a MapSeg", self.max_live_segments)
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+
+// Input logs start with this, followed by the format version.
+const MAGIC: &[u8; 8] = b"RUMLOG\0\0";
+const VERSION: u32 = 1;
+
+// Record tags. Input and end-of-input records carry the instruction count
+// at which the guest consumed them.
+const INPUT: u8 = b'I';
+const END_OF_INPUT: u8 = b'E';
+const OUTPUT: u8 = b'O';
+
+/// One I/O event of a recorded run.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Event {
+    /// An Input instruction executed after `at` instructions read `byte`,
+    /// or found the end of input when `byte` is `None`.
+    Input { at: u64, byte: Option<u8> },
+    /// An Output instruction wrote `byte`.
+    Output { byte: u8 },
+}
+
+/// Why a run stopped matching its recording.
+#[derive(Debug)]
+pub enum JournalError {
+    Io(io::Error),
+    Mismatch(String),
+}
+
+impl From<io::Error> for JournalError {
+    fn from(error: io::Error) -> Self {
+        JournalError::Io(error)
+    }
+}
+
+/// Records or replays every byte the guest consumes, so a run can be
+/// reproduced exactly.
+pub enum Journal {
+    Off,
+    Record(Recorder),
+    Replay(Replayer),
+}
+
+/// Appends the guest's I/O events to a log file as they happen.
+pub struct Recorder {
+    writer: Box<dyn Write + Send>,
+}
+
+impl Recorder {
+    pub fn new(mut writer: Box<dyn Write + Send>) -> io::Result<Self> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_be_bytes())?;
+        Ok(Self { writer })
+    }
+
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(Box::new(BufWriter::new(File::create(path)?)))
+    }
+
+    pub fn record(&mut self, event: Event) -> io::Result<()> {
+        match event {
+            Event::Input { at, byte: Some(byte) } => {
+                self.writer.write_all(&[INPUT])?;
+                self.writer.write_all(&at.to_be_bytes())?;
+                self.writer.write_all(&[byte])
+            }
+            Event::Input { at, byte: None } => {
+                self.writer.write_all(&[END_OF_INPUT])?;
+                self.writer.write_all(&at.to_be_bytes())
+            }
+            Event::Output { byte } => self.writer.write_all(&[OUTPUT, byte]),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Feeds a recorded run's input back to the guest and checks that the
+/// guest asks for it at the same points and writes the same output.
+pub struct Replayer {
+    events: VecDeque<Event>,
+}
+
+impl Replayer {
+    pub fn new(events: Vec<Event>) -> Self {
+        Self { events: events.into() }
+    }
+
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(read_log(&mut BufReader::new(File::open(path)?))?))
+    }
+
+    /// The recorded input for an Input instruction executed after `at` instructions.
+    pub fn input(&mut self, at: u64) -> Result<Option<u8>, JournalError> {
+        match self.events.pop_front() {
+            Some(Event::Input { at: recorded, byte }) if recorded == at => Ok(byte),
+            Some(Event::Input { at: recorded, .. }) => Err(JournalError::Mismatch(format!(
+                "input read after {} instructions, but the recording read it after {}",
+                at, recorded
+            ))),
+            Some(Event::Output { byte }) => Err(JournalError::Mismatch(format!(
+                "input read after {} instructions, but the recording wrote {:#04x} first",
+                at, byte
+            ))),
+            None => Err(JournalError::Mismatch(format!(
+                "input read after {} instructions, past the end of the recording",
+                at
+            ))),
+        }
+    }
+
+    /// Checks a byte the guest wrote against the recording.
+    pub fn output(&mut self, byte: u8) -> Result<(), JournalError> {
+        match self.events.pop_front() {
+            Some(Event::Output { byte: recorded }) if recorded == byte => Ok(()),
+            Some(Event::Output { byte: recorded }) => Err(JournalError::Mismatch(format!(
+                "wrote {:#04x} where the recording wrote {:#04x}",
+                byte, recorded
+            ))),
+            Some(Event::Input { at, .. }) => Err(JournalError::Mismatch(format!(
+                "wrote {:#04x} where the recording read input after {} instructions",
+                byte, at
+            ))),
+            None => Err(JournalError::Mismatch(format!(
+                "wrote {:#04x} past the end of the recording",
+                byte
+            ))),
+        }
+    }
+
+    /// Events the guest has not reached yet.
+    pub fn remaining(&self) -> usize {
+        self.events.len()
+    }
+}
+
+fn read_exact<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Reads every event of a log written by a `Recorder`. A log cut short in
+/// the middle of a record, as a crash can leave it, ends at the last
+/// complete record.
+pub fn read_log(reader: &mut impl Read) -> io::Result<Vec<Event>> {
+    if &read_exact::<8>(reader)? != MAGIC {
+        return Err(io::Error::new(ErrorKind::InvalidData, "not a rum input log"));
+    }
+    let version = u32::from_be_bytes(read_exact(reader)?);
+    if version != VERSION {
+        return Err(io::Error::new(ErrorKind::InvalidData, format!("unsupported log version {}", version)));
+    }
+    let mut events = Vec::new();
+    loop {
+        let mut tag = [0; 1];
+        if reader.read(&mut tag)? == 0 {
+            return Ok(events);
+        }
+        let event = match tag[0] {
+            INPUT => read_exact::<9>(reader).map(|record| Event::Input {
+                at: u64::from_be_bytes(record[..8].try_into().unwrap()),
+                byte: Some(record[8]),
+            }),
+            END_OF_INPUT => read_exact(reader).map(|at| Event::Input { at: u64::from_be_bytes(at), byte: None }),
+            OUTPUT => read_exact::<1>(reader).map(|byte| Event::Output { byte: byte[0] }),
+            tag => return Err(io::Error::new(ErrorKind::InvalidData, format!("bad record tag {:#04x}", tag))),
+        };
+        match event {
+            Ok(event) => events.push(event),
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(events),
+            Err(e) => return Err(e),
+        }
+    }
+}
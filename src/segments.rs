@@ -0,0 +1,583 @@
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
+use crate::parser::{self, Decoded};
+
+// Hands out program versions, so that no two states of segment 0 share one,
+// even across different managers.
+static NEXT_PROGRAM_VERSION: AtomicU64 = AtomicU64::new(0);
+
+fn next_program_version() -> u64 {
+    NEXT_PROGRAM_VERSION.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Why a segment operation could not be carried out.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum SegmentError {
+    /// The identifier does not name a mapped segment.
+    Unmapped(u32),
+    /// The offset is past the end of the segment.
+    OutOfBounds { segment: u32, offset: u32, len: usize },
+    /// The segment was already unmapped.
+    DoubleUnmap(u32),
+    /// Segment 0 holds the running program and can never be unmapped.
+    UnmapZero,
+    /// Mapping the segment would go over one of the quotas.
+    QuotaExceeded { quota: Quota, limit: u64 },
+}
+
+impl fmt::Display for SegmentError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SegmentError::Unmapped(segment) => write!(f, "segment {} is not mapped", segment),
+            SegmentError::OutOfBounds { segment, offset, len } => {
+                write!(f, "offset {} is out of bounds for segment {} ({} words)", offset, segment, len)
+            }
+            SegmentError::DoubleUnmap(segment) => write!(f, "segment {} was already unmapped", segment),
+            SegmentError::UnmapZero => write!(f, "segment 0 cannot be unmapped"),
+            SegmentError::QuotaExceeded { quota, limit } => {
+                let what = match quota {
+                    Quota::TotalWords => "words in total",
+                    Quota::SegmentWords => "words in one segment",
+                    Quota::Segments => "mapped segments",
+                };
+                write!(f, "memory quota exceeded: at most {} {}", limit, what)
+            }
+        }
+    }
+}
+
+/// The limits a map can run into.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Quota {
+    /// Words across every mapped segment, segment 0 included.
+    TotalWords,
+    /// Words in a single segment.
+    SegmentWords,
+    /// Mapped segments, segment 0 included.
+    Segments,
+}
+
+// How many decoded programs to keep for segments that a LoadProg may load
+// again. Programs that switch between a few big segments mostly stay within
+// this.
+const CACHED_PROGRAMS: usize = 8;
+
+// How many stores into segment 0 the log holds before it starts over. A
+// reader that falls further behind than this rebuilds what it built from
+// the current version instead.
+const LOGGED_WRITES: usize = 1 << 16;
+
+// A segment's words, either its own or shared with other segments after a
+// LoadProg. Stores copy shared words first.
+enum Words {
+    Owned(Vec<u32>),
+    Shared(Arc<Vec<u32>>),
+}
+
+impl Words {
+    fn as_slice(&self) -> &[u32] {
+        match self {
+            Words::Owned(words) => words,
+            Words::Shared(words) => words,
+        }
+    }
+
+    #[cold]
+    fn unshare(&mut self) {
+        if let Words::Shared(words) = std::mem::replace(self, Words::Owned(vec![])) {
+            *self = Words::Owned(Arc::try_unwrap(words).unwrap_or_else(|words| words.to_vec()));
+        }
+    }
+
+    // The words as a buffer other segments can share.
+    fn share(&mut self) -> Arc<Vec<u32>> {
+        if let Words::Owned(words) = self {
+            *self = Words::Shared(Arc::new(std::mem::take(words)));
+        }
+        match self {
+            Words::Shared(words) => Arc::clone(words),
+            Words::Owned(_) => unreachable!(),
+        }
+    }
+}
+
+// The decoding of a buffer segment 0 shared until a LoadProg replaced it,
+// which stays valid for as long as the buffer exists, since shared buffers
+// never change.
+struct CachedProgram {
+    words: Weak<Vec<u32>>,
+    decoded: Vec<Decoded>,
+    version: u64,
+}
+
+// The stores into segment 0, for readers that each remember how many they
+// have seen.
+#[derive(Default)]
+struct WriteLog {
+    // the offsets of the last stores logged, oldest first
+    offsets: Vec<u32>,
+    // stores logged in all
+    count: u64,
+    // the count when segment 0 last became a new version; stores before it
+    // were into words nothing built from the current version has seen
+    version_start: u64,
+}
+
+impl WriteLog {
+    fn push(&mut self, offset: u32) {
+        if self.offsets.len() == LOGGED_WRITES {
+            self.offsets.clear();
+        }
+        self.offsets.push(offset);
+        self.count += 1;
+    }
+
+    fn new_version(&mut self) {
+        self.offsets.clear();
+        self.version_start = self.count;
+    }
+
+    fn since(&self, since: u64) -> Option<&[u32]> {
+        let first = self.count - self.offsets.len() as u64;
+        let since = since.max(self.version_start);
+        // a count past the end comes from another manager's log
+        if since < first || since > self.count {
+            return None;
+        }
+        Some(&self.offsets[(since - first) as usize..])
+    }
+}
+
+// Decodes a program word for word, marking its fused sequences if asked to.
+fn decode(program: &[u32], fusion: bool) -> Vec<Decoded> {
+    let mut decoded: Vec<Decoded> = program.iter().map(|&inst| parser::predecode(inst)).collect();
+    if fusion {
+        parser::fuse(&mut decoded);
+    }
+    decoded
+}
+
+/// Where a segment's words are, for compiled code to reach them without a
+/// call. Unmapped segments have no words.
+#[cfg(feature = "jit")]
+#[repr(C)]
+pub(crate) struct View {
+    pub(crate) words: *mut u32,
+    pub(crate) len: u32,
+    // whether stores may go straight to the words: not for segment 0, whose
+    // decoded copy has to keep up, nor for words shared with other segments
+    pub(crate) writable: u32,
+}
+
+/// Caps on the memory a guest can claim. `None` means unlimited.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default)]
+pub struct Quotas {
+    pub max_total_words: Option<u64>,
+    pub max_segment_words: Option<u32>,
+    pub max_segments: Option<u64>,
+}
+
+/// Owns every segment of the machine's memory and hands out identifiers.
+/// Segment 0 always exists; identifiers of unmapped segments are recycled
+/// by later maps, and identifier 0 is never handed out.
+///
+/// Segments are copied on write: loading a segment as the program shares
+/// its words with segment 0 until either of them is stored to. Decoded
+/// programs are kept for the last few segments loaded this way and left
+/// unchanged, so loading one of them again takes the same time however big
+/// it is.
+pub struct SegmentManager {
+    // None marks an identifier that was mapped once and is now free
+    mem_segs: Vec<Option<Words>>,
+    // free identifiers, reused last-in first-out
+    unmap_segs: Vec<u32>,
+    // segment 0 decoded word for word, the words stored to since marked stale
+    decoded: Vec<Decoded>,
+    // whether the decoding marks fused sequences
+    fusion: bool,
+    program_version: u64,
+    // most recently replaced last
+    programs: Vec<CachedProgram>,
+    // the stores into segment 0, kept only once something asks for them
+    program_writes: Option<WriteLog>,
+    // words across every mapped segment
+    live_words: u64,
+    // every segment's view, by identifier
+    #[cfg(feature = "jit")]
+    views: Vec<View>,
+    /// Checked on every map and every LoadProg.
+    pub quotas: Quotas,
+}
+
+// SAFETY: the views only point into the manager's own segments
+#[cfg(feature = "jit")]
+unsafe impl Send for SegmentManager {}
+
+impl Default for SegmentManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SegmentManager {
+    /// Memory holding nothing but an empty segment 0.
+    pub fn new() -> Self {
+        let mut segments = Self {
+            mem_segs: vec![Some(Words::Owned(vec![]))],
+            unmap_segs: vec![],
+            decoded: vec![],
+            fusion: false,
+            program_version: next_program_version(),
+            programs: vec![],
+            program_writes: None,
+            live_words: 0,
+            #[cfg(feature = "jit")]
+            views: vec![],
+            quotas: Quotas::default(),
+        };
+        segments.refresh_view(0);
+        segments
+    }
+
+    /// Replaces segment 0 with a freshly loaded program.
+    pub fn load_program(&mut self, program: Vec<u32>) {
+        self.live_words = self.live_words - self.program().len() as u64 + program.len() as u64;
+        self.decoded = decode(&program, self.fusion);
+        self.mem_segs[0] = Some(Words::Owned(program));
+        self.refresh_view(0);
+        self.new_program(next_program_version());
+    }
+
+    fn new_program(&mut self, version: u64) {
+        self.program_version = version;
+        if let Some(writes) = &mut self.program_writes {
+            writes.new_version();
+        }
+    }
+
+    // Makes segment 0 a copy of a shared buffer, decoding it unless it was
+    // loaded recently, and keeps the decoding of the buffer it shared before.
+    fn share_program(&mut self, words: Arc<Vec<u32>>) {
+        if let Some(Words::Shared(program)) = &self.mem_segs[0] {
+            if Arc::ptr_eq(program, &words) {
+                return;
+            }
+        }
+        // a live Weak keeps the buffer's address from being reused
+        let cached = self.programs.iter().position(|program| program.words.as_ptr() == Arc::as_ptr(&words));
+        let (decoded, version) = match cached {
+            Some(index) => {
+                let program = self.programs.remove(index);
+                (program.decoded, program.version)
+            }
+            None => (decode(&words, self.fusion), next_program_version()),
+        };
+        let old_words = self.mem_segs[0].replace(Words::Shared(words));
+        self.refresh_view(0);
+        let old_decoded = std::mem::replace(&mut self.decoded, decoded);
+        let old_version = self.program_version;
+        self.new_program(version);
+        // a shared buffer is one segment 0 never stored to
+        if let Some(Words::Shared(old_words)) = old_words {
+            self.programs.push(CachedProgram {
+                words: Arc::downgrade(&old_words),
+                decoded: old_decoded,
+                version: old_version,
+            });
+        }
+        self.programs.retain(|program| program.words.strong_count() > 0);
+        if self.programs.len() > CACHED_PROGRAMS {
+            self.programs.remove(0);
+        }
+    }
+
+    // Brings a segment's view up to date after the segment was mapped,
+    // unmapped, shared or copied.
+    #[cfg(feature = "jit")]
+    fn refresh_view(&mut self, segment: u32) {
+        let view = match &mut self.mem_segs[segment as usize] {
+            Some(Words::Owned(words)) => {
+                View { words: words.as_mut_ptr(), len: words.len() as u32, writable: (segment != 0) as u32 }
+            }
+            Some(Words::Shared(words)) => View { words: words.as_ptr() as *mut u32, len: words.len() as u32, writable: 0 },
+            None => View { words: std::ptr::null_mut(), len: 0, writable: 0 },
+        };
+        match self.views.get_mut(segment as usize) {
+            Some(old) => *old = view,
+            None => self.views.push(view),
+        }
+    }
+
+    #[cfg(not(feature = "jit"))]
+    fn refresh_view(&mut self, _segment: u32) {}
+
+    /// Every segment's view, by identifier. Any change to the segments may
+    /// move them.
+    #[cfg(feature = "jit")]
+    pub(crate) fn views(&self) -> &[View] {
+        &self.views
+    }
+
+    /// Changes every time segment 0 is replaced, so code translated from it
+    /// can tell when it has gone stale. Stores into it are reported by
+    /// `program_writes` instead.
+    pub fn program_version(&self) -> u64 {
+        self.program_version
+    }
+
+    /// How many stores into segment 0 have been logged; what a reader of
+    /// `program_writes` has caught up to once it has seen them all.
+    pub fn program_write_count(&self) -> u64 {
+        self.program_writes.as_ref().map_or(0, |writes| writes.count)
+    }
+
+    /// Offsets in segment 0 stored to in its current version since the
+    /// `since`th logged store, or None when the log no longer reaches back
+    /// that far, so that the reader has to forget everything it built from
+    /// the version. Each reader keeps its own count, so readers that catch
+    /// up at different times do not lose stores to each other. Nothing is
+    /// logged until the first call.
+    pub fn program_writes(&mut self, since: u64) -> Option<&[u32]> {
+        self.program_writes.get_or_insert_with(WriteLog::default).since(since)
+    }
+
+    /// Has the interpreter run the sequences of instructions it knows as one
+    /// operation, or stop doing so. Off by default: on sandmark they make up
+    /// about 3% of the instructions run and save no measurable time.
+    pub fn set_fusion(&mut self, fusion: bool) {
+        if fusion != self.fusion {
+            self.fusion = fusion;
+            self.decoded = decode(self.program(), fusion);
+            self.programs.clear();
+        }
+    }
+
+    /// Segment 0 as decoded instructions, one per word. Words stored to
+    /// since they were last decoded are marked for `redecode`.
+    pub(crate) fn decoded(&self) -> &[Decoded] {
+        &self.decoded
+    }
+
+    /// Segment 0, the running program.
+    pub fn program(&self) -> &[u32] {
+        self.mem_segs[0].as_ref().unwrap().as_slice()
+    }
+
+    pub fn get(&self, segment: u32) -> Option<&[u32]> {
+        self.mem_segs.get(segment as usize)?.as_ref().map(Words::as_slice)
+    }
+
+    pub fn is_mapped(&self, segment: u32) -> bool {
+        self.get(segment).is_some()
+    }
+
+    /// Words across every mapped segment, segment 0 included.
+    pub fn live_words(&self) -> u64 {
+        self.live_words
+    }
+
+    /// Number of mapped segments, segment 0 included.
+    pub fn live_segments(&self) -> u64 {
+        (self.mem_segs.len() - self.unmap_segs.len()) as u64
+    }
+
+    // Checks that swapping a segment of `old_len` words for one of `new_len`
+    // words, or adding one when `old_len` is None, stays within the quotas.
+    fn check_quotas(&self, old_len: Option<usize>, new_len: u32) -> Result<(), SegmentError> {
+        let exceeded = |quota, limit| Err(SegmentError::QuotaExceeded { quota, limit });
+        if let Some(limit) = self.quotas.max_segment_words {
+            if new_len > limit {
+                return exceeded(Quota::SegmentWords, limit as u64);
+            }
+        }
+        if let Some(limit) = self.quotas.max_total_words {
+            let live_words = self.live_words - old_len.unwrap_or(0) as u64 + new_len as u64;
+            if live_words > limit {
+                return exceeded(Quota::TotalWords, limit);
+            }
+        }
+        if let Some(limit) = self.quotas.max_segments {
+            if old_len.is_none() && self.live_segments() >= limit {
+                return exceeded(Quota::Segments, limit);
+            }
+        }
+        Ok(())
+    }
+
+    /// mem[segment][offset]
+    #[inline(always)]
+    pub fn load(&self, segment: u32, offset: u32) -> Result<u32, SegmentError> {
+        match self.mem_segs.get(segment as usize) {
+            Some(Some(Words::Owned(words))) if (offset as usize) < words.len() => Ok(words[offset as usize]),
+            _ => self.load_elsewhere(segment, offset),
+        }
+    }
+
+    // Loads from shared words, and reports loads that fault.
+    #[cold]
+    fn load_elsewhere(&self, segment: u32, offset: u32) -> Result<u32, SegmentError> {
+        let seg = self.get(segment).ok_or(SegmentError::Unmapped(segment))?;
+        seg.get(offset as usize)
+            .copied()
+            .ok_or(SegmentError::OutOfBounds { segment, offset, len: seg.len() })
+    }
+
+    /// mem[segment][offset] := value
+    #[inline(always)]
+    pub fn store(&mut self, segment: u32, offset: u32, value: u32) -> Result<(), SegmentError> {
+        match self.mem_segs.get_mut(segment as usize) {
+            Some(Some(Words::Owned(words))) if (offset as usize) < words.len() => {
+                words[offset as usize] = value;
+                if segment == 0 {
+                    self.stored_program(offset);
+                }
+                Ok(())
+            }
+            _ => self.store_elsewhere(segment, offset, value),
+        }
+    }
+
+    // Keeps segment 0's decoding and the log of writes to it in step with a
+    // store into it.
+    #[inline]
+    fn stored_program(&mut self, offset: u32) {
+        parser::invalidate(&mut self.decoded, offset as usize, self.fusion);
+        if let Some(writes) = &mut self.program_writes {
+            writes.push(offset);
+        }
+    }
+
+    // store, for offsets out of bounds and for segments that are unmapped or
+    // share their words
+    #[cold]
+    fn store_elsewhere(&mut self, segment: u32, offset: u32, value: u32) -> Result<(), SegmentError> {
+        let len = self.get(segment).ok_or(SegmentError::Unmapped(segment))?.len();
+        if offset as usize >= len {
+            return Err(SegmentError::OutOfBounds { segment, offset, len });
+        }
+        if segment == 0 {
+            self.unshare_program();
+        } else {
+            self.mem_segs[segment as usize].as_mut().unwrap().unshare();
+            self.refresh_view(segment);
+        }
+        self.store(segment, offset, value)
+    }
+
+    /// Predecodes afresh a word of segment 0 stored to since it was last
+    /// predecoded, and returns it.
+    #[cold]
+    pub(crate) fn redecode(&mut self, offset: usize) -> Decoded {
+        let program = self.mem_segs[0].as_ref().unwrap().as_slice();
+        parser::redecode(&mut self.decoded, program, offset, self.fusion);
+        self.decoded[offset]
+    }
+
+    // Gives segment 0 words of its own, parting it from the buffer it shares
+    // and from anything built from that buffer's version.
+    #[cold]
+    fn unshare_program(&mut self) {
+        self.mem_segs[0].as_mut().unwrap().unshare();
+        self.refresh_view(0);
+        self.program_version = next_program_version();
+        if let Some(writes) = &mut self.program_writes {
+            writes.new_version();
+        }
+    }
+
+    /// Maps a new zero-filled segment and returns its identifier.
+    pub fn map(&mut self, len: u32) -> Result<u32, SegmentError> {
+        self.check_quotas(None, len)?;
+        // zeroed memory comes straight from the allocator, page by page
+        let new_segment = Words::Owned(vec![0; len as usize]);
+        self.live_words += len as u64;
+        // Check if we already have any unmapped mem_segs and if so reuse
+        let segment = if let Some(segment) = self.unmap_segs.pop() {
+            self.mem_segs[segment as usize] = Some(new_segment);
+            segment
+        } else {
+            self.mem_segs.push(Some(new_segment));
+            (self.mem_segs.len() - 1) as u32
+        };
+        self.refresh_view(segment);
+        Ok(segment)
+    }
+
+    /// Unmaps a segment, freeing its storage and making its identifier
+    /// available to later maps.
+    pub fn unmap(&mut self, segment: u32) -> Result<(), SegmentError> {
+        if segment == 0 {
+            return Err(SegmentError::UnmapZero);
+        }
+        match self.mem_segs.get_mut(segment as usize) {
+            None => Err(SegmentError::Unmapped(segment)),
+            Some(None) => Err(SegmentError::DoubleUnmap(segment)),
+            Some(slot) => {
+                self.live_words -= slot.take().unwrap().as_slice().len() as u64;
+                self.unmap_segs.push(segment);
+                self.refresh_view(segment);
+                Ok(())
+            }
+        }
+    }
+
+    /// Replaces segment 0 with a copy of another segment. The two share their
+    /// words until one is stored to, so this takes the same time however big
+    /// the segment is, apart from decoding it the first time it is loaded.
+    /// Replacing segment 0 with itself, or with a segment it still shares its
+    /// words with, does nothing.
+    pub fn replace_program(&mut self, segment: u32) -> Result<(), SegmentError> {
+        if segment != 0 {
+            let len = self.get(segment).ok_or(SegmentError::Unmapped(segment))?.len();
+            self.check_quotas(Some(self.program().len()), len as u32)?;
+            self.live_words = self.live_words - self.program().len() as u64 + len as u64;
+            let words = self.mem_segs[segment as usize].as_mut().unwrap().share();
+            self.refresh_view(segment);
+            self.share_program(words);
+        }
+        Ok(())
+    }
+
+    // Every identifier ever handed out, None where unmapped, and the free list.
+    pub(crate) fn parts(&self) -> (impl Iterator<Item = Option<&[u32]>>, &[u32]) {
+        (self.mem_segs.iter().map(|seg| seg.as_ref().map(Words::as_slice)), &self.unmap_segs)
+    }
+
+    // Rebuilds memory from the pieces `parts` returned, keeping the quotas.
+    pub(crate) fn from_parts(mem_segs: Vec<Option<Vec<u32>>>, unmap_segs: Vec<u32>, quotas: Quotas) -> Self {
+        let live_words = mem_segs.iter().flatten().map(|seg| seg.len() as u64).sum();
+        let mem_segs: Vec<_> = mem_segs.into_iter().map(|seg| seg.map(Words::Owned)).collect();
+        let mut segments = Self {
+            decoded: decode(mem_segs[0].as_ref().unwrap().as_slice(), false),
+            fusion: false,
+            mem_segs,
+            unmap_segs,
+            program_version: next_program_version(),
+            programs: vec![],
+            program_writes: None,
+            live_words,
+            #[cfg(feature = "jit")]
+            views: vec![],
+            quotas,
+        };
+        for segment in 0..segments.mem_segs.len() {
+            segments.refresh_view(segment as u32);
+        }
+        segments
+    }
+
+    /// Identifiers of every mapped segment, in increasing order.
+    pub fn mapped(&self) -> impl Iterator<Item = u32> + '_ {
+        self.mem_segs
+            .iter()
+            .enumerate()
+            .filter(|(_, seg)| seg.is_some())
+            .map(|(segment, _)| segment as u32)
+    }
+
+    /// Identifiers waiting to be reused, in the order they will be handed out.
+    pub fn unmapped(&self) -> impl Iterator<Item = u32> + '_ {
+        self.unmap_segs.iter().rev().copied()
+    }
+}
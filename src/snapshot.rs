@@ -0,0 +1,181 @@
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::Path;
+use crate::segments::SegmentManager;
+use crate::um::UniversalMachine;
+
+// Snapshot files start with this, followed by the format version.
+const MAGIC: &[u8; 8] = b"RUMSNAP\0";
+const VERSION: u32 = 1;
+
+/// The full state of a machine, minus its devices and settings. Saved as a
+/// versioned big-endian binary file:
+///
+/// ```text
+/// magic "RUMSNAP\0", version u32
+/// program counter u32, registers 8 x u32
+/// instructions executed u64, input bytes consumed u64
+/// identifier count u32, then per identifier:
+///     0u8 if unmapped, or 1u8, length u32 and the words
+/// free list length u32, then the free identifiers
+/// ```
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Snapshot {
+    pub program_counter: u32,
+    pub registers: [u32; 8],
+    pub instruction_count: u64,
+    pub input_consumed: u64,
+    /// Every segment identifier handed out so far, `None` where unmapped.
+    pub segments: Vec<Option<Vec<u32>>>,
+    /// Free identifiers in the order the segment manager keeps them.
+    pub unmapped: Vec<u32>,
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message.to_string())
+}
+
+fn read_u8(reader: &mut impl Read) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+impl Snapshot {
+    /// Copies the state of a machine.
+    pub fn capture(um: &UniversalMachine) -> Self {
+        let (segments, unmapped) = um.segments.parts();
+        Self {
+            program_counter: um.program_counter as u32,
+            registers: um.registers,
+            instruction_count: um.instruction_count,
+            input_consumed: um.input_consumed,
+            segments: segments.map(|segment| segment.map(<[u32]>::to_vec)).collect(),
+            unmapped: unmapped.to_vec(),
+        }
+    }
+
+    /// Puts a machine back into the saved state, ready to run. Its devices,
+    /// settings and quotas are left alone.
+    pub fn restore(&self, um: &mut UniversalMachine) {
+        um.program_counter = self.program_counter as usize;
+        um.registers = self.registers;
+        um.instruction_count = self.instruction_count;
+        um.input_consumed = self.input_consumed;
+        um.segments = SegmentManager::from_parts(self.segments.clone(), self.unmapped.clone(), um.segments.quotas);
+        um.resume();
+    }
+
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(MAGIC)?;
+        writer.write_all(&VERSION.to_be_bytes())?;
+        writer.write_all(&self.program_counter.to_be_bytes())?;
+        for register in self.registers {
+            writer.write_all(&register.to_be_bytes())?;
+        }
+        writer.write_all(&self.instruction_count.to_be_bytes())?;
+        writer.write_all(&self.input_consumed.to_be_bytes())?;
+        writer.write_all(&(self.segments.len() as u32).to_be_bytes())?;
+        for segment in &self.segments {
+            match segment {
+                None => writer.write_all(&[0])?,
+                Some(words) => {
+                    writer.write_all(&[1])?;
+                    writer.write_all(&(words.len() as u32).to_be_bytes())?;
+                    for word in words {
+                        writer.write_all(&word.to_be_bytes())?;
+                    }
+                }
+            }
+        }
+        writer.write_all(&(self.unmapped.len() as u32).to_be_bytes())?;
+        for segment in &self.unmapped {
+            writer.write_all(&segment.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Reads a snapshot, checking that its segment table is consistent.
+    pub fn read_from(reader: &mut impl Read) -> io::Result<Self> {
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid("not a rum snapshot"));
+        }
+        let version = read_u32(reader)?;
+        if version != VERSION {
+            return Err(invalid(&format!("unsupported snapshot version {}", version)));
+        }
+        let program_counter = read_u32(reader)?;
+        let mut registers = [0; 8];
+        for register in registers.iter_mut() {
+            *register = read_u32(reader)?;
+        }
+        let instruction_count = read_u64(reader)?;
+        let input_consumed = read_u64(reader)?;
+        let count = read_u32(reader)?;
+        let mut segments = Vec::new();
+        for _ in 0..count {
+            segments.push(match read_u8(reader)? {
+                0 => None,
+                1 => {
+                    let len = read_u32(reader)?;
+                    // grow as words arrive so a corrupt length cannot claim all memory up front
+                    let mut words = Vec::new();
+                    for _ in 0..len {
+                        words.push(read_u32(reader)?);
+                    }
+                    Some(words)
+                }
+                _ => return Err(invalid("bad segment marker")),
+            });
+        }
+        let free = read_u32(reader)?;
+        let mut unmapped = Vec::new();
+        for _ in 0..free {
+            unmapped.push(read_u32(reader)?);
+        }
+
+        if segments.first().is_none_or(Option::is_none) {
+            return Err(invalid("segment 0 is not mapped"));
+        }
+        let free_set: HashSet<u32> = unmapped.iter().copied().collect();
+        let holes = segments.iter().filter(|segment| segment.is_none()).count();
+        if free_set.len() != unmapped.len()
+            || free_set.len() != holes
+            || free_set.iter().any(|&id| segments.get(id as usize).is_none_or(Option::is_some))
+        {
+            return Err(invalid("free list does not match the unmapped segments"));
+        }
+        Ok(Self { program_counter, registers, instruction_count, input_consumed, segments, unmapped })
+    }
+
+    /// Writes the snapshot to a file. The file is replaced in one step, so a
+    /// crash while saving leaves the previous snapshot intact.
+    pub fn save(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let mut writer = BufWriter::new(File::create(&temp)?);
+        self.write_to(&mut writer)?;
+        writer.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        fs::rename(&temp, path)
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::read_from(&mut BufReader::new(File::open(path)?))
+    }
+}
@@ -161,4 +161,1044 @@
 //         instructions::seg_load(&mut um, &3, &1, &2);
 //         assert_eq!(1, um.registers[3]);
 //     }
-// }
\ No newline at end of file
+// }
+
+use crate::error::UmError;
+use crate::um::{Status, UniversalMachine};
+
+// Builds a machine whose segment 0 holds the given instruction words.
+fn machine(program: &[u32]) -> UniversalMachine {
+    let mut um = UniversalMachine::new();
+    um.segments.load_program(program.to_vec());
+    um
+}
+
+#[test]
+fn step_returns_control_to_host() {
+    let mut um = machine(&[
+        0b_1101_0010_0000_0000_0000_0000_0010_1010, // r1 := 42
+        0b_0111_0000_0000_0000_0000_0000_0000_0000, // halt
+    ]);
+    assert_eq!(Ok(Status::Running), um.step());
+    assert_eq!(42, um.registers[1]);
+    assert_eq!(Ok(Status::Halted), um.step());
+    // a halted machine stays halted
+    assert_eq!(Ok(Status::Halted), um.step());
+    assert_eq!(2, um.program_counter);
+}
+
+#[test]
+fn run_stops_on_invalid_opcode() {
+    let mut um = machine(&[0b_1111_0000_0000_0000_0000_0000_0000_0000]);
+    let fault = um.run().unwrap_err();
+    assert!(matches!(fault, UmError::InvalidOpcode(_)));
+    assert_eq!(Status::Faulted, um.status());
+    assert_eq!(Some(&fault), um.fault());
+}
+
+#[test]
+fn division_by_zero_faults_with_context() {
+    let mut um = machine(&[
+        0b_1101_0010_0000_0000_0000_0000_0000_1111, // r1 := 15
+        0b_0101_0000_0000_0000_0000_0000_0000_1010, // r0 := r1 / r2
+    ]);
+    let fault = um.run().unwrap_err();
+    assert!(matches!(fault, UmError::DivisionByZero(_)));
+    assert_eq!(1, fault.pc());
+    assert_eq!(Some(0b_0101_0000_0000_0000_0000_0000_0000_1010), fault.instruction());
+    assert_eq!(15, fault.registers()[1]);
+    // the program counter stays on the faulting instruction
+    assert_eq!(1, um.program_counter);
+}
+
+#[test]
+fn segment_faults() {
+    // r0 := m[r1][r2] with segment 0 holding a single word
+    let mut um = machine(&[0b_0001_0000_0000_0000_0000_0000_0000_1010]);
+    um.registers[2] = 5;
+    assert!(matches!(um.run(), Err(UmError::OffsetOutOfBounds { segment: 0, offset: 5, len: 1, .. })));
+
+    um = machine(&[0b_0001_0000_0000_0000_0000_0000_0000_1010]);
+    um.registers[1] = 3;
+    assert!(matches!(um.run(), Err(UmError::UnmappedSegment { segment: 3, .. })));
+
+    // running off the end of segment 0
+    um = machine(&[0b_1101_0010_0000_0000_0000_0000_0000_0000]);
+    assert!(matches!(um.run(), Err(UmError::ProgramCounterOutOfBounds { pc: 1, len: 1, .. })));
+}
+
+#[test]
+fn scripted_input_and_captured_output() {
+    use crate::devices::{BufferInput, BufferOutput};
+    let output = BufferOutput::new();
+    let mut um = UniversalMachine::with_io(Box::new(BufferInput::new("hi")), Box::new(output.clone()));
+    um.segments.load_program(vec![
+        0b_1011_0000_0000_0000_0000_0000_0000_0001, // input r1
+        0b_1010_0000_0000_0000_0000_0000_0000_0001, // output r1
+        0b_1011_0000_0000_0000_0000_0000_0000_0001, // input r1
+        0b_1010_0000_0000_0000_0000_0000_0000_0001, // output r1
+        0b_0111_0000_0000_0000_0000_0000_0000_0000, // halt
+    ]);
+    assert_eq!(Ok(Status::Halted), um.run());
+    assert_eq!(b"hi".to_vec(), output.contents());
+}
+
+#[test]
+fn empty_channel_waits_for_input() {
+    use crate::devices::{BufferOutput, ChannelInput};
+    use std::sync::mpsc::channel;
+    let (sender, receiver) = channel();
+    let mut um = UniversalMachine::with_io(Box::new(ChannelInput::new(receiver)), Box::new(BufferOutput::new()));
+    um.segments.load_program(vec![
+        0b_1011_0000_0000_0000_0000_0000_0000_0001, // input r1
+        0b_0111_0000_0000_0000_0000_0000_0000_0000, // halt
+    ]);
+    assert_eq!(Ok(Status::WaitingForInput), um.run());
+    assert_eq!(0, um.program_counter);
+    sender.send(b'x').unwrap();
+    assert_eq!(Ok(Status::Halted), um.run());
+    assert_eq!(b'x' as u32, um.registers[1]);
+}
+
+// Reads one byte from an empty input device into r1.
+fn read_past_end(end_of_input: crate::um::EndOfInput) -> u32 {
+    use crate::devices::{BufferInput, BufferOutput};
+    let mut um = UniversalMachine::with_io(Box::new(BufferInput::new("")), Box::new(BufferOutput::new()));
+    um.end_of_input = end_of_input;
+    um.segments.load_program(vec![
+        0b_1011_0000_0000_0000_0000_0000_0000_0001, // input r1
+        0b_0111_0000_0000_0000_0000_0000_0000_0000, // halt
+    ]);
+    assert_eq!(Ok(Status::Halted), um.run());
+    um.registers[1]
+}
+
+#[test]
+fn end_of_input_sets_every_bit() {
+    assert_eq!(0xFFFF_FFFF, read_past_end(crate::um::EndOfInput::default()));
+}
+
+#[test]
+fn legacy_end_of_input_loads_one() {
+    assert_eq!(1, read_past_end(crate::um::EndOfInput::Legacy));
+}
+
+#[test]
+fn output_is_binary_safe() {
+    use crate::devices::{BufferInput, BufferOutput};
+    let output = BufferOutput::new();
+    let mut um = UniversalMachine::with_io(Box::new(BufferInput::new("")), Box::new(output.clone()));
+    um.segments.load_program(vec![
+        0b_1101_0010_0000_0000_0000_0000_1111_1111, // r1 := 255
+        0b_1010_0000_0000_0000_0000_0000_0000_0001, // output r1
+        0b_1101_0010_0000_0000_0000_0000_1000_0000, // r1 := 128
+        0b_1010_0000_0000_0000_0000_0000_0000_0001, // output r1
+        0b_0111_0000_0000_0000_0000_0000_0000_0000, // halt
+    ]);
+    assert_eq!(Ok(Status::Halted), um.run());
+    assert_eq!(vec![255, 128], output.contents());
+}
+
+#[test]
+fn segment_manager_recycles_identifiers() {
+    use crate::segments::{SegmentError, SegmentManager};
+    let mut segments = SegmentManager::new();
+    let first = segments.map(2).unwrap();
+    let second = segments.map(3).unwrap();
+    assert_ne!(0, first);
+    assert_ne!(first, second);
+    segments.store(first, 1, 7).unwrap();
+    assert_eq!(Ok(7), segments.load(first, 1));
+
+    segments.unmap(first).unwrap();
+    assert_eq!(Err(SegmentError::Unmapped(first)), segments.load(first, 1));
+    assert_eq!(Err(SegmentError::DoubleUnmap(first)), segments.unmap(first));
+    assert_eq!(Err(SegmentError::UnmapZero), segments.unmap(0));
+
+    // the freed identifier comes back zero-filled
+    assert_eq!(Ok(first), segments.map(4));
+    assert_eq!(Ok(0), segments.load(first, 1));
+    assert_eq!(vec![0, first, second], segments.mapped().collect::<Vec<_>>());
+}
+
+#[test]
+fn double_unmap_faults() {
+    let mut um = machine(&[
+        0b_1000_0000_0000_0000_0000_0000_0000_1010, // map r1 := new segment of r2 words
+        0b_1001_0000_0000_0000_0000_0000_0000_0001, // unmap r1
+        0b_1001_0000_0000_0000_0000_0000_0000_0001, // unmap r1
+    ]);
+    assert!(matches!(um.run(), Err(UmError::DoubleUnmap { segment: 1, .. })));
+    assert_eq!(2, um.program_counter);
+}
+
+#[test]
+fn store_into_segment_zero_redecodes() {
+    let mut um = machine(&[
+        0b_1101_0010_0000_0000_0000_0000_0010_1010, // r1 := 42
+        0b_1111_0000_0000_0000_0000_0000_0000_0000, // invalid
+    ]);
+    um.segments.store(0, 1, 0b_0111_0000_0000_0000_0000_0000_0000_0000).unwrap(); // halt
+    assert_eq!(Ok(Status::Halted), um.run());
+    assert_eq!(42, um.registers[1]);
+}
+
+#[test]
+fn loaded_programs_copy_on_write() {
+    use crate::parser::predecode;
+    use crate::segments::SegmentManager;
+    let mut segments = SegmentManager::new();
+    let code = segments.map(2).unwrap();
+    segments.store(code, 0, 7).unwrap();
+    segments.replace_program(code).unwrap();
+    assert_eq!(&[7, 0], segments.program());
+    // reloading a segment the program still shares its words with changes nothing
+    let version = segments.program_version();
+    segments.replace_program(code).unwrap();
+    assert_eq!(version, segments.program_version());
+
+    // stores to either side stay on that side
+    segments.store(code, 1, 8).unwrap();
+    segments.store(0, 0, 9).unwrap();
+    assert_eq!(&[9, 0], segments.program());
+    assert_eq!(Some(&[7, 8][..]), segments.get(code));
+    assert_eq!(4, segments.live_words());
+
+    segments.replace_program(code).unwrap();
+    assert_ne!(version, segments.program_version());
+    assert_eq!(&[7, 8], segments.program());
+
+    // switching back and forth between two segments decodes each once
+    let other = segments.map(3).unwrap();
+    segments.store(other, 2, 7 << 28).unwrap();
+    segments.replace_program(other).unwrap();
+    let other_version = segments.program_version();
+    assert_eq!(predecode(7 << 28), segments.decoded()[2]);
+    segments.replace_program(code).unwrap();
+    let version = segments.program_version();
+    segments.replace_program(other).unwrap();
+    assert_eq!(other_version, segments.program_version());
+    assert_eq!(predecode(7 << 28), segments.decoded()[2]);
+    segments.replace_program(code).unwrap();
+    assert_eq!(version, segments.program_version());
+    assert_eq!(&[7, 8], segments.program());
+}
+
+#[test]
+fn instruction_codec_round_trips() {
+    use crate::parser::{decode, encode, DecodeError, Instruction};
+    let instructions = [
+        Instruction::CMov { a: 1, b: 2, c: 3 },
+        Instruction::SegLoad { a: 7, b: 0, c: 5 },
+        Instruction::Nand { a: 4, b: 4, c: 4 },
+        Instruction::Halt,
+        Instruction::MapSeg { b: 6, c: 2 },
+        Instruction::UnmapSeg { c: 3 },
+        Instruction::Input { c: 1 },
+        Instruction::LoadProg { b: 0, c: 7 },
+        Instruction::LoadVal { a: 6, value: 0x1FF_FFFF },
+    ];
+    for instruction in instructions {
+        assert_eq!(Ok(instruction), decode(encode(&instruction)));
+    }
+    assert_eq!(0b_1101_0010_0000_0000_0000_0000_0010_1010, encode(&Instruction::LoadVal { a: 1, value: 42 }));
+    assert_eq!(0b_0111_0000_0000_0000_0000_0000_0000_0000, encode(&Instruction::Halt));
+    assert_eq!(Err(DecodeError::InvalidOpcode(14)), decode(0xE000_0000));
+}
+
+#[test]
+fn set_replaces_only_its_field() {
+    use crate::parser::{get, set, RB, VL};
+    let inst = 0xFFFF_FFFF;
+    assert_eq!(0xFFFF_FFC7, set(&RB, &inst, 0));
+    assert_eq!(5, get(&RB, &set(&RB, &inst, 5)));
+    assert_eq!(0xFE00_0000 | 0x1FF_FFFF, set(&VL, &inst, 0xFFFF_FFFF));
+}
+
+#[test]
+fn budget_stops_and_resumes() {
+    use crate::parser::{encode, Instruction};
+    // r1 := r1 + r2 forever
+    let mut um = machine(&[
+        encode(&Instruction::LoadVal { a: 2, value: 1 }),
+        encode(&Instruction::Add { a: 1, b: 1, c: 2 }),
+        encode(&Instruction::LoadProg { b: 0, c: 3 }),
+    ]);
+    um.registers[3] = 1;
+    assert_eq!(Ok(Status::OutOfFuel), um.run_with_budget(5));
+    assert_eq!(5, um.instruction_count);
+    assert_eq!(2, um.registers[1]);
+    assert_eq!(Ok(Status::OutOfFuel), um.run_with_budget(4));
+    assert_eq!(9, um.instruction_count);
+    assert_eq!(4, um.registers[1]);
+}
+
+#[test]
+fn budget_reports_halt() {
+    let mut um = machine(&[0b_0111_0000_0000_0000_0000_0000_0000_0000]);
+    assert_eq!(Ok(Status::Halted), um.run_with_budget(10));
+    assert_eq!(Ok(Status::Halted), um.run_with_budget(0));
+    assert_eq!(1, um.instruction_count);
+}
+
+#[test]
+fn quotas_limit_mapping() {
+    use crate::segments::{Quota, Quotas, SegmentError, SegmentManager};
+    let mut segments = SegmentManager::new();
+    segments.load_program(vec![0; 4]);
+    segments.quotas = Quotas { max_total_words: Some(20), max_segment_words: Some(10), max_segments: Some(3) };
+    assert_eq!(Err(SegmentError::QuotaExceeded { quota: Quota::SegmentWords, limit: 10 }), segments.map(11));
+    let first = segments.map(10).unwrap();
+    assert_eq!(Err(SegmentError::QuotaExceeded { quota: Quota::TotalWords, limit: 20 }), segments.map(7));
+    segments.map(6).unwrap();
+    assert_eq!(20, segments.live_words());
+    assert_eq!(Err(SegmentError::QuotaExceeded { quota: Quota::Segments, limit: 3 }), segments.map(0));
+    segments.unmap(first).unwrap();
+    assert_eq!(10, segments.live_words());
+    assert_eq!(2, segments.live_segments());
+    // LoadProg counts the new segment 0 against the quota too
+    assert_eq!(Ok(first), segments.map(10));
+    assert_eq!(
+        Err(SegmentError::QuotaExceeded { quota: Quota::TotalWords, limit: 20 }),
+        segments.replace_program(first)
+    );
+}
+
+#[test]
+fn huge_map_faults_instead_of_aborting() {
+    use crate::parser::{encode, Instruction};
+    use crate::segments::{Quota, Quotas};
+    let mut um = machine(&[
+        encode(&Instruction::LoadVal { a: 2, value: 0x1FF_FFFF }),
+        encode(&Instruction::Mul { a: 2, b: 2, c: 2 }),
+        encode(&Instruction::MapSeg { b: 1, c: 2 }),
+    ]);
+    um.segments.quotas = Quotas { max_segment_words: Some(1 << 20), ..Quotas::default() };
+    assert!(matches!(um.run(), Err(UmError::QuotaExceeded { quota: Quota::SegmentWords, .. })));
+}
+
+#[test]
+fn snapshot_round_trips_and_resumes() {
+    use crate::parser::{encode, Instruction};
+    use crate::snapshot::Snapshot;
+    let program = [
+        encode(&Instruction::LoadVal { a: 2, value: 3 }),
+        encode(&Instruction::MapSeg { b: 1, c: 2 }),
+        encode(&Instruction::MapSeg { b: 3, c: 2 }),
+        encode(&Instruction::UnmapSeg { c: 1 }),
+        encode(&Instruction::SegStore { a: 3, b: 0, c: 2 }),
+        encode(&Instruction::LoadVal { a: 4, value: 9 }),
+        encode(&Instruction::Halt),
+    ];
+    let mut um = machine(&program);
+    assert_eq!(Ok(Status::OutOfFuel), um.run_with_budget(5));
+
+    let snapshot = Snapshot::capture(&um);
+    let mut bytes = Vec::new();
+    snapshot.write_to(&mut bytes).unwrap();
+    let read = Snapshot::read_from(&mut bytes.as_slice()).unwrap();
+    assert_eq!(snapshot, read);
+    assert_eq!(vec![1], read.unmapped);
+
+    let mut resumed = machine(&[]);
+    read.restore(&mut resumed);
+    assert_eq!(Ok(Status::Halted), resumed.run());
+    assert_eq!(9, resumed.registers[4]);
+    assert_eq!(7, resumed.instruction_count);
+    assert_eq!(Ok(3), resumed.segments.load(2, 0));
+    // the free identifier is still handed out next
+    assert_eq!(Ok(1), resumed.segments.map(1));
+
+    bytes[0] = b'X';
+    assert!(Snapshot::read_from(&mut bytes.as_slice()).is_err());
+}
+
+#[test]
+fn recorded_run_replays_and_detects_divergence() {
+    use crate::devices::{BufferInput, BufferOutput};
+    use crate::parser::{encode, Instruction};
+    use crate::replay::{read_log, Event, Journal, Recorder, Replayer};
+    // echoes one byte of input twice, then reads the end of input
+    let program = vec![
+        encode(&Instruction::Input { c: 1 }),
+        encode(&Instruction::Output { c: 1 }),
+        encode(&Instruction::Output { c: 1 }),
+        encode(&Instruction::Input { c: 2 }),
+        encode(&Instruction::Halt),
+    ];
+    let path = std::env::temp_dir().join(format!("rum-replay-{}.log", std::process::id()));
+    let mut um = UniversalMachine::with_io(Box::new(BufferInput::new("x")), Box::new(BufferOutput::new()));
+    um.segments.load_program(program.clone());
+    um.journal = Journal::Record(Recorder::create(&path).unwrap());
+    assert_eq!(Ok(Status::Halted), um.run());
+    if let Journal::Record(recorder) = &mut um.journal {
+        recorder.flush().unwrap();
+    }
+
+    let events = read_log(&mut std::fs::File::open(&path).unwrap()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        vec![
+            Event::Input { at: 0, byte: Some(b'x') },
+            Event::Output { byte: b'x' },
+            Event::Output { byte: b'x' },
+            Event::Input { at: 3, byte: None },
+        ],
+        events
+    );
+
+    // replaying ignores the input device
+    let output = BufferOutput::new();
+    let mut um = UniversalMachine::with_io(Box::new(BufferInput::new("y")), Box::new(output.clone()));
+    um.segments.load_program(program.clone());
+    um.journal = Journal::Replay(Replayer::new(events.clone()));
+    assert_eq!(Ok(Status::Halted), um.run());
+    assert_eq!(b"xx".to_vec(), output.contents());
+
+    // a guest that writes something else no longer matches
+    let mut changed = program;
+    changed[2] = encode(&Instruction::Output { c: 0 });
+    let mut um = UniversalMachine::with_io(Box::new(BufferInput::new("")), Box::new(BufferOutput::new()));
+    um.segments.load_program(changed);
+    um.journal = Journal::Replay(Replayer::new(events));
+    assert!(matches!(um.run(), Err(UmError::ReplayMismatch { context, .. }) if context.pc == 2));
+}
+
+#[test]
+fn trace_shows_changes_and_honours_filters() {
+    use crate::devices::BufferOutput;
+    use crate::parser::{encode, Instruction};
+    use crate::trace::{TraceFormat, Tracer};
+    let mut um = machine(&[
+        encode(&Instruction::LoadVal { a: 1, value: 2 }),
+        encode(&Instruction::MapSeg { b: 2, c: 1 }),
+        encode(&Instruction::SegStore { a: 2, b: 0, c: 1 }),
+        encode(&Instruction::Halt),
+    ]);
+    let trace = BufferOutput::new();
+    let mut tracer = Tracer::new(Box::new(trace.clone()), TraceFormat::Text).unwrap();
+    tracer.filter.window = Some(1..3);
+    um.tracer = Some(tracer);
+    assert_eq!(Ok(Status::Halted), um.run());
+    let text = String::from_utf8(trace.contents()).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(2, lines.len());
+    assert!(lines[0].ends_with("map r2, r1               r2=0x1 map m[1] (2 words)"), "{}", lines[0]);
+    assert!(lines[1].contains("store r2, r0, r1"));
+    assert!(lines[1].ends_with("m[1][0]=0x2"));
+}
+
+#[test]
+fn profiler_counts_opcodes_addresses_and_maps() {
+    use crate::parser::{encode, Instruction};
+    use crate::profile::Profiler;
+    // maps and unmaps five words twice, jumping back once
+    let mut um = machine(&[
+        encode(&Instruction::LoadVal { a: 1, value: 5 }),
+        encode(&Instruction::LoadVal { a: 4, value: 9 }),
+        encode(&Instruction::MapSeg { b: 2, c: 1 }),
+        encode(&Instruction::UnmapSeg { c: 2 }),
+        encode(&Instruction::LoadVal { a: 3, value: 2 }),
+        encode(&Instruction::CMov { a: 3, b: 4, c: 6 }),
+        encode(&Instruction::LoadVal { a: 6, value: 1 }),
+        encode(&Instruction::LoadProg { b: 0, c: 3 }),
+        encode(&Instruction::Halt),
+        encode(&Instruction::Halt),
+    ]);
+    um.profiler = Some(Profiler::new());
+    assert_eq!(Ok(Status::Halted), um.run());
+    let profiler = um.profiler.unwrap();
+    assert_eq!(2, profiler.opcodes[8]);
+    assert_eq!(2, profiler.opcodes[12]);
+    assert_eq!(vec![1, 1, 2, 2, 2, 2, 2, 2, 0, 1], profiler.pcs);
+    assert_eq!(vec![(2, 2), (3, 2)], profiler.hot_spots(2));
+    assert_eq!(Some(&1), profiler.jump_targets.get(&2));
+    assert_eq!(Some(&1), profiler.jump_targets.get(&9));
+    assert_eq!(2, profiler.map_sizes[3]);
+    assert_eq!(0, profiler.program_loads);
+    let mut summary = Vec::new();
+    profiler.write_summary(&mut summary, 1).unwrap();
+    let summary = String::from_utf8(summary).unwrap();
+    assert!(summary.starts_with("15 instructions executed"));
+    assert!(summary.contains("00000002: 80000011  map r2, r1"), "{}", summary);
+}
+
+#[test]
+fn debugger_stops_at_breakpoints_and_pokes_state() {
+    use crate::debugger::Debugger;
+    use crate::parser::{encode, Instruction};
+    let mut debugger = Debugger::new(machine(&[
+        encode(&Instruction::LoadVal { a: 1, value: 2 }),
+        encode(&Instruction::LoadVal { a: 2, value: 3 }),
+        encode(&Instruction::Add { a: 3, b: 1, c: 2 }),
+        encode(&Instruction::Halt),
+    ]));
+    let mut out = Vec::new();
+    let commands = ["break add", "continue", "set r2 40", "step", "", "x 0 3", "poke 5 0 1", "list 18446744073709551615"];
+    for command in commands {
+        assert!(debugger.command(command, &mut out).unwrap());
+    }
+    assert!(!debugger.command("quit", &mut out).unwrap());
+    let text = String::from_utf8(out).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!("breakpoint 0 at every add", lines[0]);
+    assert_eq!("breakpoint 0: => 00000002: 300000ca  add r3, r1, r2", lines[1]);
+    assert_eq!("=> 00000003: 70000000  halt", lines[3]);
+    assert_eq!("guest halted", lines[4]);
+    assert_eq!("m[0][0x3] = 0x70000000 1879048192", lines[5]);
+    assert_eq!("error: segment 5 is not mapped", lines[6]);
+    // listing more words than there are stops at the end of segment 0
+    assert_eq!(Some(&"=> 00000004: past the end of segment 0"), lines.last());
+    assert_eq!(12, lines.len());
+    assert_eq!(42, debugger.um.registers[3]);
+}
+
+#[test]
+fn gdb_stub_reads_memory_and_stops_at_breakpoints() {
+    use crate::gdbstub::GdbStub;
+    use crate::parser::{encode, Instruction};
+    let mut stub = GdbStub::new(machine(&[
+        encode(&Instruction::LoadVal { a: 1, value: 3 }),
+        encode(&Instruction::MapSeg { b: 2, c: 1 }),
+        encode(&Instruction::SegStore { a: 2, b: 0, c: 1 }),
+        encode(&Instruction::Halt),
+    ]));
+    let mut never = || false;
+    assert_eq!("d2000003", stub.respond("m0,4", &mut never));
+    assert_eq!("OK", stub.respond("Z0,8,4", &mut never));
+    assert_eq!("S05", stub.respond("c", &mut never));
+    assert_eq!("00000008", stub.respond("p8", &mut never));
+    assert_eq!("00000003", stub.respond("p1", &mut never));
+    assert_eq!("S05", stub.respond("s", &mut never));
+    assert_eq!("000000030000", stub.respond("m100000000,6", &mut never));
+    assert_eq!("OK", stub.respond("M100000007,1:2a", &mut never));
+    assert_eq!(Some(&[3, 42, 0][..]), stub.um.segments.get(1));
+    assert_eq!("E01", stub.respond("m200000000,4", &mut never));
+    assert_eq!("W00", stub.respond("c", &mut never));
+}
+
+#[test]
+fn dump_annotates_text_and_data() {
+    use crate::dump::dump;
+    use crate::parser::{encode, Instruction};
+    let mut words = vec![];
+    for c in "Hi!\n".bytes() {
+        words.push(encode(&Instruction::LoadVal { a: 1, value: c as u32 }));
+        words.push(encode(&Instruction::Output { c: 1 }));
+    }
+    words.extend([0x4f, 0x4b, 0x21, 0x21, 0xf0000000, 0x70000001]);
+    let mut out = Vec::new();
+    dump(&words, true, &mut out).unwrap();
+    let text = String::from_utf8(out).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!("00000000: d2000048  loadval r1, 72           ; \"Hi!\\n\"", lines[0]);
+    assert_eq!("00000001: a0000001  out r1", lines[1]);
+    assert_eq!("00000006: d200000a  loadval r1, 10           ; '\\n'", lines[6]);
+    assert_eq!("00000008: 0000004f  cmov r1, r1, r7          ; data \"OK!!\"", lines[8]);
+    assert_eq!("00000009: 0000004b  cmov r1, r1, r3          ; data", lines[9]);
+    assert_eq!("0000000c: f0000000  .word 0xf0000000         ; data", lines[12]);
+    assert_eq!("0000000d: 70000001  halt                     ; data? unused bits set", lines[13]);
+}
+
+#[test]
+fn assembler_resolves_labels_constants_and_directives() {
+    use crate::asm::{assemble, AsmError};
+    use crate::parser::{encode, Instruction};
+    let source = "
+        .equ LF, '\\n'
+start:  loadval r1, text      ; forward reference
+        loadval r2, LF
+        loadval r3, end-start
+        halt
+text:   .string \"a;b\"
+        .word 0x10, 0b11, -1, ';'
+end:    .space 2
+";
+    let expected = vec![
+        encode(&Instruction::LoadVal { a: 1, value: 4 }),
+        encode(&Instruction::LoadVal { a: 2, value: 10 }),
+        encode(&Instruction::LoadVal { a: 3, value: 11 }),
+        encode(&Instruction::Halt),
+        'a' as u32, ';' as u32, 'b' as u32,
+        16, 3, u32::MAX, ';' as u32,
+        0, 0,
+    ];
+    assert_eq!(Ok(expected), assemble(source));
+    assert_eq!(
+        Err(AsmError { line: 3, message: "expected a register r0 to r7, found \"r8\"".to_string() }),
+        assemble("halt\n\nout r8\n")
+    );
+    assert_eq!(Err(AsmError { line: 2, message: "x is defined twice".to_string() }), assemble("x: halt\nx: halt"));
+    // operators inside character constants are part of them
+    assert_eq!(Ok(vec!['+' as u32, 100 - '-' as u32, 'a' as u32 + 1]), assemble(".equ x, 100\n.word '+', x-'-', 'a'+1"));
+    assert_eq!(
+        Err(AsmError { line: 2, message: ".space 4294967295 does not fit in a segment".to_string() }),
+        assemble("halt\n.space 0xffffffff")
+    );
+}
+
+#[test]
+fn assembler_reads_back_what_the_disassembler_prints() {
+    use crate::asm::assemble;
+    use crate::parser::decode;
+    for word in [0x300000ca, 0x80000011, 0x90000002, 0xa0000001, 0xb0000007, 0xc0000031, 0xde000010, 0x70000000] {
+        let text = decode(word).unwrap().to_string();
+        assert_eq!(Ok(vec![word]), assemble(&text), "{}", text);
+    }
+}
+
+#[test]
+fn umasm_macros_lower_through_temporaries() {
+    use crate::devices::{BufferInput, BufferOutput};
+    use crate::umasm::assemble;
+    let source = "
+.section init
+.temps r5, r6, r7
+.reg sp, r2
+        sp := endstack
+        goto main
+.section text
+main:   r3 := 3
+loop:   push r3 on stack sp
+        r3 := r3 - 1
+        if (r3 != 0) goto loop
+        pop r1 off stack sp      // the last push was 1
+        r1 := r1 + '0'
+        output r1
+        r4 := 0xfffffffe
+        r4 := -r4
+        if (r4 == 2) goto done
+        output 'x'
+done:   halt
+.section stk
+        .space 10
+endstack:
+";
+    let output = BufferOutput::new();
+    let mut um = UniversalMachine::with_io(Box::new(BufferInput::new("")), Box::new(output.clone()));
+    um.segments.load_program(assemble(source).unwrap());
+    assert_eq!(Ok(Status::Halted), um.run());
+    assert_eq!(b"1".to_vec(), output.contents());
+    assert_eq!(0, um.registers[3]);
+
+    let error = assemble(".temps r7\nr1 := 5 | 6\n").unwrap_err();
+    assert_eq!((2, "not enough temporaries; declare more with .temps"), (error.line, error.message.as_str()));
+    let error = assemble(".temps r7\nr1 := r7 + r2\n").unwrap_err();
+    assert_eq!("r7 is reserved as a temporary", error.message);
+
+    // unmap is also the native instruction when it names a register
+    let unmap = crate::asm::assemble("unmap r3").unwrap();
+    assert_eq!(Ok(unmap.clone()), assemble("unmap r3"));
+    assert_eq!(Ok(unmap.clone()), assemble(".reg seg, r3\nunmap seg"));
+    assert_eq!(Ok(unmap), assemble("unmap m[r3]"));
+}
+
+#[test]
+fn umasm_macros_fit_in_two_temporaries() {
+    use crate::devices::{BufferInput, BufferOutput};
+    use crate::umasm::assemble;
+    // the COMP40 setup, with a conditional jump back to address 0
+    let source = "
+.temps r6, r7
+.zero r0
+start:  r1 := r1 + 1
+        if (r1 != 3) goto start
+        if (r1 == 5) goto bad
+        r2 := r1
+        if (r1 != r2) goto bad
+        r4 := r1 | 8
+        r4 := r4 + 'A'
+        output r4
+        halt
+bad:    output 'x'
+        halt
+text:   .string \"a := b\"
+";
+    let words = assemble(source).unwrap();
+    let text: Vec<u32> = "a := b".chars().map(|c| c as u32).collect();
+    assert!(words.ends_with(&text));
+    let output = BufferOutput::new();
+    let mut um = UniversalMachine::with_io(Box::new(BufferInput::new("")), Box::new(output.clone()));
+    um.segments.load_program(words);
+    assert_eq!(Ok(Status::Halted), um.run());
+    assert_eq!(b"L".to_vec(), output.contents());
+}
+
+#[test]
+fn cfg_resolves_loadval_jumps_and_marks_unreachable() {
+    use crate::asm::assemble;
+    use crate::cfg::{Cfg, Exit};
+    let words = assemble(
+        "
+        loadval r1, skip
+        loadprog r0, r1
+        halt                ; never reached
+skip:   loadval r2, a
+        loadval r3, b
+        cmov r2, r3, r4
+        loadprog r0, r2
+a:      halt
+b:      .word 0xe0000000
+",
+    )
+    .unwrap();
+    let cfg = Cfg::build(&words);
+    let summary: Vec<(usize, usize, Exit, Vec<usize>, bool)> =
+        cfg.blocks.iter().map(|b| (b.start, b.end, b.exit.clone(), b.successors.clone(), b.reachable)).collect();
+    assert_eq!(
+        vec![
+            (0, 2, Exit::Jump { targets: vec![3], unresolved: false }, vec![3], true),
+            (2, 3, Exit::Halt, vec![], false),
+            (3, 7, Exit::Jump { targets: vec![7, 8], unresolved: false }, vec![7, 8], true),
+            (7, 8, Exit::Halt, vec![], true),
+            (8, 9, Exit::Fault, vec![], true),
+        ],
+        summary
+    );
+    assert_eq!(Some(3), cfg.block_at(5).map(|b| b.start));
+    assert_eq!(0, cfg.unresolved().count());
+
+    let mut dot = Vec::new();
+    cfg.write_dot(&words, &mut dot).unwrap();
+    let dot = String::from_utf8(dot).unwrap();
+    assert!(dot.contains("b2 [label=\"00000002: halt\\l\", style=dashed, color=gray, fontcolor=gray];"), "{}", dot);
+    assert!(dot.contains("b3 -> b7;") && dot.contains("b3 -> b8;"));
+    assert!(!dot.contains("unknown"));
+}
+
+#[test]
+fn cfg_only_trusts_registers_it_can_pin_down() {
+    use crate::asm::assemble;
+    use crate::cfg::{Cfg, Exit};
+    let words = assemble(
+        "
+        loadval r1, again
+        loadprog r7, r1     ; r7 is 0 only until the jump back to address 0
+        halt
+again:  loadval r7, 1
+        loadval r6, 0
+        loadprog r0, r6
+        load r1, r0, r0
+        loadval r2, 2
+        loadprog r1, r2     ; r1 may name another segment
+",
+    )
+    .unwrap();
+    let exits: Vec<Exit> = Cfg::build(&words).blocks.into_iter().map(|b| b.exit).collect();
+    assert_eq!(
+        vec![
+            Exit::Jump { targets: vec![3], unresolved: true },
+            Exit::Halt,
+            Exit::Jump { targets: vec![0], unresolved: false },
+            Exit::Jump { targets: vec![2], unresolved: true },
+        ],
+        exits
+    );
+}
+
+#[test]
+fn aot_translation_falls_back_when_segment_zero_changes() {
+    use crate::aot::translate;
+    use crate::asm::assemble;
+    use crate::um::EndOfInput;
+    use std::process::Command;
+    // patches the instruction at target before reaching it, then puts it
+    // back and runs it again
+    let words = assemble(
+        "
+        loadval r2, patch
+        load r3, r0, r2
+        loadval r4, target
+        load r6, r0, r4
+        store r0, r4, r3
+target: loadval r5, 'x'
+        out r5
+        store r0, r4, r6
+        loadval r1, done
+        loadval r7, target
+        cmov r1, r7, r3
+        loadval r3, 0
+        loadprog r0, r1
+done:   halt
+patch:  .word 0xda000079    ; loadval r5, 'y'
+",
+    )
+    .unwrap();
+    let mut c = Vec::new();
+    translate(&words, EndOfInput::AllOnes, &mut c).unwrap();
+    let c = String::from_utf8(c).unwrap();
+    assert!(c.contains("L5: /* loadval r5, 120 */\n    r5 = 120u;"), "{}", c);
+    assert!(c.contains("case 13: goto L13;"));
+    assert!(c.contains("        store_program(r4, r3);\n        if (dirty[0]) {\n            pc = 5;\n            goto leave;"));
+
+    // compiling needs a C compiler, which not every machine running the tests has
+    let dir = std::env::temp_dir();
+    let source = dir.join(format!("rum-aot-{}.c", std::process::id()));
+    let binary = dir.join(format!("rum-aot-{}", std::process::id()));
+    std::fs::write(&source, &c).unwrap();
+    let Ok(status) = Command::new("cc").arg("-o").arg(&binary).arg(&source).status() else {
+        return;
+    };
+    assert!(status.success());
+    let output = Command::new(&binary).output().unwrap();
+    let _ = std::fs::remove_file(&source);
+    let _ = std::fs::remove_file(&binary);
+    assert_eq!(b"yx".to_vec(), output.stdout);
+}
+
+// A hot loop through a mapped segment that rewrites one of its own
+// instructions every time round, then divides by zero. It prints 150.
+#[cfg(feature = "jit")]
+fn self_modifying_loop() -> Vec<u32> {
+    crate::asm::assemble(
+        "
+        nand r7, r0, r0         ; r7 = -1
+        loadval r1, 10
+        map r4, r1
+        loadval r2, 100
+        loadval r6, 0
+loop:   loadval r1, 1
+        load r3, r4, r1
+        mul r5, r2, r2
+        add r3, r3, r5
+        store r4, r1, r3
+        map r3, r1              ; a scratch segment, unmapped straight away
+        unmap r3
+        nand r5, r2, r1         ; r5 = r2 & 1
+        nand r5, r5, r5
+        loadval r1, patches
+        add r1, r1, r5
+        load r5, r0, r1
+        loadval r1, patched
+        store r0, r1, r5
+patched: loadval r5, 0
+        add r6, r6, r5
+        add r2, r2, r7
+        loadval r5, done
+        loadval r1, loop
+        cmov r5, r1, r2
+        loadprog r0, r5
+done:   out r6
+        div r1, r1, r0
+patches: .word 0xda000001, 0xda000002   ; loadval r5, 1 and loadval r5, 2
+",
+    )
+    .unwrap()
+}
+
+type Stops = Vec<(Result<Status, UmError>, usize, [u32; 8], u64)>;
+
+// Runs a program `budget` instructions at a time on a machine `configure`
+// sets up, and returns where it stopped each time, what it printed and what
+// segment 0 ended up holding.
+fn run_in_slices(words: &[u32], budget: u64, configure: impl Fn(&mut UniversalMachine)) -> (Stops, Vec<u8>, Vec<u32>) {
+    use crate::devices::{BufferInput, BufferOutput};
+    let output = BufferOutput::new();
+    let mut um = UniversalMachine::with_io(Box::new(BufferInput::new("")), Box::new(output.clone()));
+    configure(&mut um);
+    um.segments.load_program(words.to_vec());
+    let mut stops = vec![];
+    loop {
+        let result = um.run_with_budget(budget);
+        stops.push((result.clone(), um.program_counter, um.registers, um.instruction_count));
+        if result != Ok(Status::OutOfFuel) {
+            break;
+        }
+    }
+    (stops, output.contents(), um.segments.program().to_vec())
+}
+
+fn interpret_only(_um: &mut UniversalMachine) {
+    #[cfg(feature = "jit")]
+    {
+        _um.jit = None;
+    }
+}
+
+#[cfg(feature = "jit")]
+#[test]
+fn jit_matches_the_interpreter() {
+    let words = self_modifying_loop();
+    for budget in [u64::MAX, 1000, 40, 7, 1] {
+        let jit = run_in_slices(&words, budget, |_| {});
+        assert_eq!(run_in_slices(&words, budget, interpret_only), jit, "budget {}", budget);
+    }
+    assert_eq!(vec![150], run_in_slices(&words, u64::MAX, |_| {}).1);
+}
+
+// The start of midmark, which maps and unmaps segments in its hot loops,
+// on the jit and the interpreter: a cut-down form of the check below that
+// runs with the other tests.
+#[cfg(feature = "jit")]
+#[test]
+fn jit_matches_the_interpreter_on_the_start_of_midmark() {
+    use crate::devices::{BufferInput, BufferOutput};
+    let words = crate::rumload::load(Some(concat!(env!("CARGO_MANIFEST_DIR"), "/midmark.um")));
+    let run = |configure: fn(&mut UniversalMachine)| {
+        let output = BufferOutput::new();
+        let mut um = UniversalMachine::with_io(Box::new(BufferInput::new("")), Box::new(output.clone()));
+        configure(&mut um);
+        um.segments.load_program(words.clone());
+        let result = um.run_with_budget(5_000_000);
+        (result, um.program_counter, um.registers, um.instruction_count, output.contents())
+    };
+    let jit = run(|_| {});
+    assert_eq!(Ok(Status::OutOfFuel), jit.0);
+    assert_eq!(run(interpret_only), jit);
+}
+
+// Runs the benchmarks on the interpreter and the jit, checks
+// that they agree, and prints how long each took. It takes minutes, so it
+// only runs on request:
+// cargo test --release --features jit -- --ignored --nocapture
+#[cfg(feature = "jit")]
+#[test]
+#[ignore]
+fn backends_agree_on_the_benchmarks() {
+    use std::time::Instant;
+    let backends = [("interpreter", interpret_only as fn(&mut _)), ("jit", |_| {})];
+    for name in ["midmark.um", "sandmark.umz"] {
+        let words = crate::rumload::load(Some(&format!("{}/{}", env!("CARGO_MANIFEST_DIR"), name)));
+        let mut results = vec![];
+        for (backend, configure) in backends {
+            let started = Instant::now();
+            results.push(run_in_slices(&words, u64::MAX, configure));
+            println!("{} on {}: {:.2?}", name, backend, started.elapsed());
+        }
+        assert_eq!(results[0], results[1], "{} on jit", name);
+    }
+}
+
+#[cfg(feature = "jit")]
+#[test]
+fn stores_reopen_addresses_without_a_block() {
+    use crate::blocks::{Block, BlockIndex, Slot};
+    use crate::segments::SegmentManager;
+    struct Word;
+    impl Block for Word {
+        type Entry = ();
+        fn len(&self) -> usize {
+            1
+        }
+        fn entry(&self) {}
+    }
+    let mut segments = SegmentManager::new();
+    segments.load_program(vec![0; 3]);
+    let mut index = BlockIndex::default();
+    index.sync(&mut segments);
+    index.insert(1, None::<Word>);
+    // the address counts as covered, so a backend that sees the store does
+    // not take it for a store to data and drop it
+    assert_eq!(&[0, 1, 0], index.coverage());
+    segments.store(0, 1, 7).unwrap();
+    index.sync(&mut segments);
+    assert!(matches!(index.get(1), Some(Slot::Cold(0))));
+    assert_eq!(&[0, 0, 0], index.coverage());
+
+    // an index still sees a store that another, as after swapping jits,
+    // caught up with first
+    let mut other = BlockIndex::default();
+    other.sync(&mut segments);
+    other.insert(2, Some(Word));
+    segments.store(0, 2, 7).unwrap();
+    index.sync(&mut segments);
+    other.sync(&mut segments);
+    assert!(matches!(other.get(2), Some(Slot::Cold(0))));
+}
+
+#[test]
+fn machines_can_move_between_threads() {
+    fn assert_send<T: Send>() {}
+    assert_send::<UniversalMachine>();
+}
+
+#[test]
+fn fused_sequences_keep_their_meaning() {
+    use crate::devices::{BufferInput, BufferOutput};
+    use crate::parser::Fusion;
+    // the add after `loadval r3, 3` is rewritten into a mul every other time
+    // round, and the loop comes back in at the second word of a jump
+    let words = crate::asm::assemble(
+        "
+        nand r7, r0, r0         ; r7 = -1
+        loadval r2, 6
+        loadval r6, 1
+        loadval r5, loop
+        loadval r1, 0
+        loadprog r1, r5
+loop:   loadval r1, 1
+        nand r5, r2, r1
+        nand r5, r5, r5         ; r5 = r2 & 1
+        loadval r1, patches
+        add r1, r1, r5
+        load r5, r0, r1
+        loadval r1, patched
+        store r0, r1, r5
+        loadval r3, 3
+patched: add r6, r6, r3
+        add r2, r2, r7
+        loadval r5, done
+        loadval r1, back
+        cmov r5, r1, r2
+        loadprog r0, r5
+        loadval r6, 0
+back:   loadval r5, loop
+        loadprog r0, r5
+done:   out r6
+        halt
+patches: .word 0x300001b3, 0x400001b3   ; add r6, r6, r3 and mul r6, r6, r3
+",
+    )
+    .unwrap();
+    // budgets under the length of a sequence never fuse it
+    let (stops, output, program) = run_in_slices(&words, 1, interpret_only);
+    assert_eq!(vec![144], output);
+    let fusing = |um: &mut UniversalMachine| {
+        interpret_only(um);
+        um.segments.set_fusion(true);
+    };
+    for budget in [u64::MAX, 7, 3, 2] {
+        let (fused_stops, fused_output, fused_program) = run_in_slices(&words, budget, fusing);
+        assert_eq!(stops.last(), fused_stops.last(), "budget {}", budget);
+        assert_eq!((&output, &program), (&fused_output, &fused_program), "budget {}", budget);
+    }
+
+    let output = BufferOutput::new();
+    let mut um = UniversalMachine::with_io(Box::new(BufferInput::new("")), Box::new(output.clone()));
+    interpret_only(&mut um);
+    um.segments.load_program(words);
+    um.segments.set_fusion(true);
+    assert_eq!(Ok(Status::Halted), um.run());
+    assert_eq!(vec![144], output.contents());
+    // the patched add fuses on each of the three rounds that store it, the
+    // store completing the sequence; of the jumps, only the first is run
+    // from its first word
+    assert_eq!(1, um.fusions[Fusion::Jump as usize]);
+    assert_eq!(6, um.fusions[Fusion::And as usize]);
+    assert_eq!(6 + 3, um.fusions[Fusion::AddImmediate as usize]);
+
+    // a jump that would replace segment 0 runs as its three instructions,
+    // and faults at the LoadProg
+    let words = crate::asm::assemble("loadval r5, 0\nloadval r1, 9\nloadprog r1, r5").unwrap();
+    assert_eq!(run_in_slices(&words, u64::MAX, interpret_only), run_in_slices(&words, u64::MAX, fusing));
+
+    // nor does one whose LoadProg a store has turned into a halt
+    let words = crate::asm::assemble(
+        "
+        loadval r2, patch
+        load r3, r0, r2
+        loadval r4, end
+        store r0, r4, r3
+        loadval r5, 0
+        loadval r1, 0
+end:    loadprog r1, r5
+patch:  .word 0x70000000    ; halt
+",
+    )
+    .unwrap();
+    let mut um = UniversalMachine::new();
+    fusing(&mut um);
+    um.segments.load_program(words);
+    assert_eq!(Ok(Status::Halted), um.run_with_budget(100));
+    assert_eq!(0, um.fusions[Fusion::Jump as usize]);
+}
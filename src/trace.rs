@@ -0,0 +1,166 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::ops::Range;
+use std::path::Path;
+use crate::parser::{self, Instruction};
+
+// Binary traces start with this, followed by the format version.
+const MAGIC: &[u8; 8] = b"RUMTRACE";
+const VERSION: u32 = 1;
+
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum TraceFormat {
+    /// One line per instruction.
+    Text,
+    /// A "RUMTRACE" header and version u32, then one big-endian record per
+    /// instruction: count u64, pc u32, word u32, changed-register mask u8,
+    /// the new value u32 of each changed register, an effect tag u8 (0 none,
+    /// 1 store, 2 map, 3 unmap, 4 load program) and the effect's fields u32.
+    Binary,
+}
+
+/// Which executed instructions make it into the trace. Every condition that
+/// is set has to hold.
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct TraceFilter {
+    /// Addresses in segment 0.
+    pub pc_range: Option<Range<usize>>,
+    /// Bit n set traces opcode n.
+    pub opcodes: Option<u16>,
+    /// Instruction counts, where the first instruction executed is 0.
+    pub window: Option<Range<u64>>,
+}
+
+impl TraceFilter {
+    pub fn matches(&self, count: u64, pc: usize, word: u32) -> bool {
+        self.pc_range.as_ref().is_none_or(|range| range.contains(&pc))
+            && self.opcodes.is_none_or(|opcodes| opcodes & (1 << parser::op(word)) != 0)
+            && self.window.as_ref().is_none_or(|window| window.contains(&count))
+    }
+}
+
+/// What an instruction did to memory.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum MemoryEffect {
+    None,
+    Store { segment: u32, offset: u32, value: u32 },
+    Map { segment: u32, len: u32 },
+    Unmap { segment: u32 },
+    /// Segment 0 was replaced by a copy of `segment`.
+    LoadProgram { segment: u32 },
+}
+
+/// One executed instruction and its effects.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct TraceRecord {
+    /// Instructions executed before this one.
+    pub count: u64,
+    pub pc: usize,
+    pub word: u32,
+    pub before: [u32; 8],
+    pub after: [u32; 8],
+}
+
+impl TraceRecord {
+    pub fn instruction(&self) -> Instruction {
+        // only instructions that executed are traced, so the word is valid
+        parser::decode(self.word).unwrap()
+    }
+
+    /// Bit n is set when register n changed.
+    pub fn changed_registers(&self) -> u8 {
+        (0..8).filter(|&r| self.before[r] != self.after[r]).fold(0, |mask, r| mask | 1 << r)
+    }
+
+    pub fn memory_effect(&self) -> MemoryEffect {
+        let r = |register: u8| self.before[register as usize];
+        match self.instruction() {
+            Instruction::SegStore { a, b, c } => MemoryEffect::Store { segment: r(a), offset: r(b), value: r(c) },
+            Instruction::MapSeg { b, c } => MemoryEffect::Map { segment: self.after[b as usize], len: r(c) },
+            Instruction::UnmapSeg { c } => MemoryEffect::Unmap { segment: r(c) },
+            Instruction::LoadProg { b, .. } if r(b) != 0 => MemoryEffect::LoadProgram { segment: r(b) },
+            _ => MemoryEffect::None,
+        }
+    }
+}
+
+/// Writes a trace of executed instructions. Attach one to a machine's
+/// `tracer` field to trace it.
+pub struct Tracer {
+    writer: Box<dyn Write + Send>,
+    format: TraceFormat,
+    pub filter: TraceFilter,
+}
+
+impl Tracer {
+    pub fn new(mut writer: Box<dyn Write + Send>, format: TraceFormat) -> io::Result<Self> {
+        if format == TraceFormat::Binary {
+            writer.write_all(MAGIC)?;
+            writer.write_all(&VERSION.to_be_bytes())?;
+        }
+        Ok(Self { writer, format, filter: TraceFilter::default() })
+    }
+
+    pub fn create(path: impl AsRef<Path>, format: TraceFormat) -> io::Result<Self> {
+        Self::new(Box::new(BufWriter::new(File::create(path)?)), format)
+    }
+
+    /// Writes a record if it passes the filter.
+    pub fn record(&mut self, record: &TraceRecord) -> io::Result<()> {
+        if !self.filter.matches(record.count, record.pc, record.word) {
+            return Ok(());
+        }
+        match self.format {
+            TraceFormat::Text => self.write_text(record),
+            TraceFormat::Binary => self.write_binary(record),
+        }
+    }
+
+    // count pc: word  instruction  changes
+    fn write_text(&mut self, record: &TraceRecord) -> io::Result<()> {
+        let mut line = format!("{:>10} {:08x}: {:08x}  {:<24}", record.count, record.pc, record.word, record.instruction().to_string());
+        for r in 0..8 {
+            if record.changed_registers() & (1 << r) != 0 {
+                line += &format!(" r{}={:#x}", r, record.after[r]);
+            }
+        }
+        match record.memory_effect() {
+            MemoryEffect::None => {}
+            MemoryEffect::Store { segment, offset, value } => line += &format!(" m[{}][{}]={:#x}", segment, offset, value),
+            MemoryEffect::Map { segment, len } => line += &format!(" map m[{}] ({} words)", segment, len),
+            MemoryEffect::Unmap { segment } => line += &format!(" unmap m[{}]", segment),
+            MemoryEffect::LoadProgram { segment } => line += &format!(" m[0]=copy of m[{}]", segment),
+        }
+        writeln!(self.writer, "{}", line.trim_end())
+    }
+
+    fn write_binary(&mut self, record: &TraceRecord) -> io::Result<()> {
+        let w = &mut self.writer;
+        w.write_all(&record.count.to_be_bytes())?;
+        w.write_all(&(record.pc as u32).to_be_bytes())?;
+        w.write_all(&record.word.to_be_bytes())?;
+        let changed = record.changed_registers();
+        w.write_all(&[changed])?;
+        for r in 0..8 {
+            if changed & (1 << r) != 0 {
+                w.write_all(&record.after[r].to_be_bytes())?;
+            }
+        }
+        let (tag, fields) = match record.memory_effect() {
+            MemoryEffect::None => (0, vec![]),
+            MemoryEffect::Store { segment, offset, value } => (1, vec![segment, offset, value]),
+            MemoryEffect::Map { segment, len } => (2, vec![segment, len]),
+            MemoryEffect::Unmap { segment } => (3, vec![segment]),
+            MemoryEffect::LoadProgram { segment } => (4, vec![segment]),
+        };
+        w.write_all(&[tag])?;
+        for field in fields {
+            w.write_all(&field.to_be_bytes())?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
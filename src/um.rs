@@ -1,20 +1,289 @@
+use crate::devices::{InputDevice, OutputDevice, StdinInput, StdoutOutput};
+use crate::error::UmError;
+use crate::parser::{self, Fusion};
+use crate::replay::Journal;
+use crate::profile::Profiler;
+#[cfg(feature = "jit")]
+use crate::jit::Jit;
+use crate::trace::Tracer;
+use crate::segments::SegmentManager;
+
+/// Where the machine stands after executing an instruction.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Status {
+    /// The machine can execute the next instruction.
+    Running,
+    /// A Halt instruction was executed.
+    Halted,
+    /// An Input instruction could not be satisfied yet; stepping again retries it.
+    WaitingForInput,
+    /// The guest raised a fault; see `UniversalMachine::fault`.
+    Faulted,
+    /// `run_with_budget` used up its budget; running again carries on.
+    OutOfFuel,
+}
+
+/// What the Input instruction loads into $r[C] once the end of input has
+/// been signaled.
+#[derive(Debug, PartialEq, Eq, Copy, Clone, Default)]
+pub enum EndOfInput {
+    /// A word with every bit set, as the spec requires.
+    #[default]
+    AllOnes,
+    /// The value 1, which earlier versions of rum loaded. Only for programs
+    /// that were written against that behavior.
+    Legacy,
+}
+
+impl EndOfInput {
+    pub fn value(self) -> u32 {
+        match self {
+            EndOfInput::AllOnes => u32::MAX,
+            EndOfInput::Legacy => 1,
+        }
+    }
+}
+
+/// When the machine flushes its output device. Each policy also flushes at
+/// every point the ones before it do. Whatever the policy, the host should
+/// flush the output device once it is done with the machine.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Default)]
+pub enum FlushPolicy {
+    /// Only when the device's own buffer fills up.
+    Never,
+    /// When a Halt instruction executes.
+    AtHalt,
+    /// Before every Input instruction, so prompts show up before the guest
+    /// waits for a reply.
+    #[default]
+    BeforeInput,
+    /// After every newline byte.
+    OnNewline,
+}
+
 pub struct UniversalMachine {
 
     pub program_counter: usize,
     // The UM will only have 8 registers, each of which is a 32-bit word
     pub registers: [u32; 8],
-    pub mem_segs: Vec<Vec<u32>>,
-    pub unmap_segs: Vec<u32>,
+    pub segments: SegmentManager,
+    pub input: Box<dyn InputDevice>,
+    pub output: Box<dyn OutputDevice>,
+    pub end_of_input: EndOfInput,
+    pub flush_policy: FlushPolicy,
+    /// Number of instructions executed so far.
+    pub instruction_count: u64,
+    /// Number of bytes read by Input instructions so far.
+    pub input_consumed: u64,
+    /// Records or replays the guest's I/O. While replaying, input comes from
+    /// the recording instead of the input device.
+    pub journal: Journal,
+    /// Writes a trace of every executed instruction when set.
+    pub tracer: Option<Tracer>,
+    /// Counts what the guest executes when set.
+    pub profiler: Option<Profiler>,
+    /// Compiles hot code when set, which it is unless taken away. Not used
+    /// while a tracer or profiler is attached.
+    #[cfg(feature = "jit")]
+    pub jit: Option<Jit>,
+    /// How many times each fused sequence ran, indexed by `Fusion as usize`.
+    /// Only the interpreter fuses, only without a tracer or profiler, and
+    /// only once `SegmentManager::set_fusion` has turned fusion on.
+    pub fusions: [u64; Fusion::ALL.len()],
+    // Halted and Faulted are final, so the machine remembers where it stopped
+    status: Status,
+    fault: Option<UmError>,
 
 }
 
+impl Default for UniversalMachine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl UniversalMachine {
+    /// A machine wired to the process's standard input and output.
     pub fn new() -> Self {
+        Self::with_io(Box::new(StdinInput::new()), Box::new(StdoutOutput::new()))
+    }
+
+    /// A machine that reads and writes through the given devices.
+    pub fn with_io(input: Box<dyn InputDevice>, output: Box<dyn OutputDevice>) -> Self {
         Self {
             program_counter: 0,
             registers: [0; 8],
-            mem_segs: vec![vec![]],
-            unmap_segs: vec![],
+            segments: SegmentManager::new(),
+            input,
+            output,
+            end_of_input: EndOfInput::default(),
+            flush_policy: FlushPolicy::default(),
+            instruction_count: 0,
+            input_consumed: 0,
+            journal: Journal::Off,
+            tracer: None,
+            profiler: None,
+            #[cfg(feature = "jit")]
+            jit: Some(Jit::new()),
+            fusions: [0; Fusion::ALL.len()],
+            status: Status::Running,
+            fault: None,
+        }
+    }
+
+    /// The status returned by the most recent step.
+    pub fn status(&self) -> Status {
+        self.status
+    }
+
+    // Clears a halt or fault so the machine runs again from its current state.
+    pub(crate) fn resume(&mut self) {
+        self.status = Status::Running;
+        self.fault = None;
+    }
+
+    /// The fault that stopped the machine, if any.
+    pub fn fault(&self) -> Option<&UmError> {
+        self.fault.as_ref()
+    }
+
+    /// Executes a single instruction. Once the machine has halted or faulted
+    /// it stays that way: a halted machine keeps returning `Halted` and a
+    /// faulted one keeps returning its fault.
+    pub fn step(&mut self) -> Result<Status, UmError> {
+        match self.status {
+            Status::Halted => return Ok(Status::Halted),
+            Status::Faulted => return Err(self.fault.clone().unwrap()),
+            _ => {}
+        }
+        let result = parser::parse(self);
+        self.record(result)
+    }
+
+    // Remembers how the last instruction left the machine.
+    fn record(&mut self, result: Result<Status, UmError>) -> Result<Status, UmError> {
+        match result {
+            Ok(status) => {
+                self.status = status;
+                Ok(status)
+            }
+            Err(fault) => {
+                self.status = Status::Faulted;
+                self.fault = Some(fault.clone());
+                Err(fault)
+            }
         }
     }
-}
\ No newline at end of file
+
+    /// Executes instructions until the machine stops running and hands
+    /// control back to the caller.
+    pub fn run(&mut self) -> Result<Status, UmError> {
+        // no guest runs for 2^64 instructions, so this never runs out of fuel
+        self.run_with_budget(u64::MAX)
+    }
+
+    /// Like `run`, but executes at most `budget` instructions. If the budget
+    /// runs out first the machine reports `OutOfFuel` and can be resumed by
+    /// another call to `run` or `run_with_budget`.
+    pub fn run_with_budget(&mut self, budget: u64) -> Result<Status, UmError> {
+        if budget == 0 {
+            return self.out_of_fuel();
+        }
+        let status = self.step()?;
+        if status != Status::Running {
+            return Ok(status);
+        }
+        // the machine is known to be running, so skip the checks in step,
+        // and pick the dispatch loop once rather than per instruction
+        if self.tracer.is_some() || self.profiler.is_some() {
+            self.run_loop(budget - 1, parser::parse)
+        } else if cfg!(feature = "jit") && self.jit_enabled() {
+            self.run_jit(budget - 1)
+        } else {
+            self.run_fused(budget - 1)
+        }
+    }
+
+    #[inline(always)]
+    fn run_loop(
+        &mut self,
+        budget: u64,
+        parse: impl Fn(&mut Self) -> Result<Status, UmError>,
+    ) -> Result<Status, UmError> {
+        for _ in 0..budget {
+            match parse(self) {
+                Ok(Status::Running) => {}
+                result => return self.record(result),
+            }
+        }
+        self.out_of_fuel()
+    }
+
+    // Like run_loop, but runs the sequences predecoding fused as one
+    // operation for as long as the longest of them fits in the budget.
+    fn run_fused(&mut self, mut budget: u64) -> Result<Status, UmError> {
+        match parser::run_fused(self, &mut budget) {
+            Ok(Status::Running) => self.run_loop(budget, parser::parse_uninstrumented),
+            result => self.record(result),
+        }
+    }
+
+    #[cfg(feature = "jit")]
+    fn jit_enabled(&self) -> bool {
+        self.jit.is_some()
+    }
+
+    #[cfg(not(feature = "jit"))]
+    fn jit_enabled(&self) -> bool {
+        false
+    }
+
+    // Like run_loop, but lets `execute` run what it can, and count it,
+    // before each instruction the interpreter takes.
+    #[cfg(feature = "jit")]
+    #[inline(always)]
+    fn run_blocks(
+        &mut self,
+        mut budget: u64,
+        mut execute: impl FnMut(&mut Self, u64) -> u64,
+    ) -> Result<Status, UmError> {
+        loop {
+            if budget == 0 {
+                return self.out_of_fuel();
+            }
+            let executed = execute(self, budget);
+            if executed > 0 {
+                budget -= executed;
+                continue;
+            }
+            match parser::parse_uninstrumented(self) {
+                Ok(Status::Running) => budget -= 1,
+                result => return self.record(result),
+            }
+        }
+    }
+
+    // Like run_loop, but runs compiled blocks wherever there are any. Kept
+    // out of line so the interpreter's loop stays small.
+    #[cfg(feature = "jit")]
+    #[inline(never)]
+    fn run_jit(&mut self, budget: u64) -> Result<Status, UmError> {
+        let mut jit = self.jit.take().unwrap();
+        let result = self.run_blocks(budget, |um, budget| jit.execute(um, budget));
+        self.jit = Some(jit);
+        result
+    }
+
+    #[cfg(not(feature = "jit"))]
+    fn run_jit(&mut self, _budget: u64) -> Result<Status, UmError> {
+        unreachable!()
+    }
+
+    fn out_of_fuel(&mut self) -> Result<Status, UmError> {
+        if let Status::Halted | Status::Faulted = self.status {
+            return self.step();
+        }
+        self.status = Status::OutOfFuel;
+        Ok(Status::OutOfFuel)
+    }
+}
@@ -0,0 +1,590 @@
+use std::collections::HashMap;
+use crate::asm::{self, AsmError};
+
+// Words starting a umasm statement rather than a native instruction.
+const MACRO_KEYWORDS: [&str; 6] = ["output", "goto", "if", "push", "pop", "unmap"];
+
+/// An operand of a umasm statement.
+#[derive(Debug, PartialEq, Eq, Clone)]
+enum Operand {
+    Register(u8),
+    /// A value the native assembler evaluates: a number, character or label.
+    Constant(String),
+}
+
+// The temporaries a statement has not claimed yet.
+struct Temps {
+    free: Vec<u8>,
+}
+
+impl Temps {
+    fn take(&mut self) -> Result<u8, String> {
+        self.free.pop().ok_or_else(|| "not enough temporaries; declare more with .temps".to_string())
+    }
+
+    fn give(&mut self, register: u8) {
+        self.free.push(register);
+    }
+}
+
+/// Lowers the COMP40 umasm dialect to native rum assembly.
+///
+/// Besides everything the native assembler accepts, a line can hold:
+///
+/// ```text
+/// rA := X                      rA := X op Y    (op: + - * / nand & |)
+/// rA := ~X   rA := -X          rA := m[X][Y]   m[X][Y] := Z
+/// rA := map segment (X words)  unmap m[X]
+/// rA := input()                output X
+/// goto X                       goto rB in program m[rC]
+/// if (X) goto L                if (X == Y) goto L    if (X != Y) goto L
+/// if (rC) rA := X              if (rC != 0) rA := X
+/// push X on stack rS           pop rA off stack rS   pop stack rS
+/// halt
+/// ```
+///
+/// where X, Y and Z are registers or constants. Stacks live in segment 0
+/// and grow down: `push` decrements the stack pointer, then stores.
+///
+/// Directives: `.section NAME` switches sections, which are laid out in
+/// the order they first appear, `text` first when code comes before any
+/// `.section`; `.temps rX, ...` names the registers statements may
+/// clobber, `.zero rZ` promises a register that always holds 0, and
+/// `.reg NAME, rN` names a register. `.data X` is `.word X`.
+pub fn lower(source: &str) -> Result<Vec<(usize, String)>, AsmError> {
+    let mut lowerer = Lowerer {
+        sections: vec![],
+        current: None,
+        temps: vec![],
+        zero: None,
+        names: HashMap::new(),
+        labels: 0,
+    };
+    for (index, line) in source.lines().enumerate() {
+        let number = index + 1;
+        let mut rest = asm::strip_comment(line).trim();
+        let mut lines = vec![];
+        while let Some((label, after)) = asm::split_label(rest) {
+            lines.push(format!("{}:", label));
+            rest = after.trim();
+        }
+        if !rest.is_empty() {
+            lowerer
+                .statement(rest, &mut lines)
+                .map_err(|message| AsmError { line: number, message })?;
+        }
+        if !lines.is_empty() {
+            lowerer.section().extend(lines.into_iter().map(|line| (number, line)));
+        }
+    }
+    Ok(lowerer.sections.into_iter().flat_map(|(_, lines)| lines).collect())
+}
+
+/// Assembles a umasm program into the words of a program image.
+pub fn assemble(source: &str) -> Result<Vec<u32>, AsmError> {
+    asm::assemble_lines(lower(source)?)
+}
+
+struct Lowerer {
+    sections: Vec<(String, Vec<(usize, String)>)>,
+    current: Option<usize>,
+    temps: Vec<u8>,
+    zero: Option<u8>,
+    names: HashMap<String, u8>,
+    // generated labels so far
+    labels: usize,
+}
+
+// Splits at whitespace outside quotes.
+fn tokens(text: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut token = String::new();
+    let mut quote = None;
+    let mut escaped = false;
+    for c in text.chars() {
+        match (quote, c) {
+            _ if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(q), _) if c == q => quote = None,
+            (None, '\'' | '"') => quote = Some(c),
+            (None, _) if c.is_whitespace() => {
+                if !token.is_empty() {
+                    tokens.push(std::mem::take(&mut token));
+                }
+                continue;
+            }
+            _ => {}
+        }
+        token.push(c);
+    }
+    if !token.is_empty() {
+        tokens.push(token);
+    }
+    tokens
+}
+
+// Splits at the first `:=` outside quotes.
+fn split_assignment(text: &str) -> Option<(&str, &str)> {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in text.char_indices() {
+        match (quote, c) {
+            _ if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(q), _) if c == q => quote = None,
+            (None, '\'' | '"') => quote = Some(c),
+            (None, ':') if text[i + 1..].starts_with('=') => return Some((&text[..i], &text[i + 2..])),
+            _ => {}
+        }
+    }
+    None
+}
+
+// Splits `m[X][Y]` into X and Y.
+fn memory_operands(text: &str) -> Option<(&str, &str)> {
+    let inner = text.strip_prefix("m[")?.strip_suffix(']')?;
+    let (segment, offset) = inner.split_once("][")?;
+    Some((segment.trim(), offset.trim()))
+}
+
+// Takes X out of `m[X]`.
+fn segment_operand(text: &str) -> Option<&str> {
+    text.strip_prefix("m[")?.strip_suffix(']')
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+impl Lowerer {
+    // Replaces register names outside quotes with the registers they name.
+    fn resolve_names(&self, text: &str) -> String {
+        let mut resolved = String::new();
+        let mut name = String::new();
+        let mut quote = None;
+        let mut escaped = false;
+        for c in text.chars().chain(Some(' ')) {
+            if quote.is_none() && is_name_char(c) {
+                name.push(c);
+                continue;
+            }
+            match self.names.get(&name) {
+                Some(r) => resolved += &format!("r{}", r),
+                None => resolved += &name,
+            }
+            name.clear();
+            match (quote, c) {
+                _ if escaped => escaped = false,
+                (Some(_), '\\') => escaped = true,
+                (Some(q), _) if c == q => quote = None,
+                (None, '\'' | '"') => quote = Some(c),
+                _ => {}
+            }
+            resolved.push(c);
+        }
+        resolved.pop();
+        resolved
+    }
+
+    fn section(&mut self) -> &mut Vec<(usize, String)> {
+        let index = match self.current {
+            Some(index) => index,
+            None => self.switch("text"),
+        };
+        &mut self.sections[index].1
+    }
+
+    fn switch(&mut self, name: &str) -> usize {
+        let index = match self.sections.iter().position(|(section, _)| section == name) {
+            Some(index) => index,
+            None => {
+                self.sections.push((name.to_string(), vec![]));
+                self.sections.len() - 1
+            }
+        };
+        self.current = Some(index);
+        index
+    }
+
+    fn register_named(&self, text: &str) -> Option<u8> {
+        asm::parse_register(text).or_else(|| self.names.get(text).copied())
+    }
+
+    fn register(&self, text: &str) -> Result<u8, String> {
+        self.register_named(text.trim()).ok_or_else(|| format!("expected a register, found {:?}", text.trim()))
+    }
+
+    fn operand(&self, text: &str) -> Operand {
+        let text = text.trim();
+        match self.register_named(text) {
+            Some(r) => Operand::Register(r),
+            None => Operand::Constant(text.to_string()),
+        }
+    }
+
+    fn label(&mut self) -> String {
+        self.labels += 1;
+        format!("_umasm_{}", self.labels)
+    }
+
+    fn fresh_temps(&self) -> Temps {
+        Temps { free: self.temps.iter().rev().copied().collect() }
+    }
+
+    // Rejects a statement that names a register it may clobber as a temporary.
+    fn check_operands(&self, registers: &[u8]) -> Result<(), String> {
+        match registers.iter().find(|r| self.temps.contains(r)) {
+            Some(r) => Err(format!("r{} is reserved as a temporary", r)),
+            None => Ok(()),
+        }
+    }
+
+    fn statement(&mut self, text: &str, out: &mut Vec<String>) -> Result<(), String> {
+        let words = tokens(text);
+        let first = words[0].as_str();
+        match first {
+            ".section" => {
+                let [_, name] = words.as_slice() else {
+                    return Err(".section takes a name".to_string());
+                };
+                self.switch(name);
+                return Ok(());
+            }
+            ".temps" => {
+                let names = asm::split_operands(&text[".temps".len()..]);
+                self.temps = names.iter().map(|name| self.register(name)).collect::<Result<_, _>>()?;
+                return Ok(());
+            }
+            ".zero" => {
+                self.zero = Some(self.register(&text[".zero".len()..])?);
+                return Ok(());
+            }
+            ".reg" => {
+                let operands = asm::split_operands(&text[".reg".len()..]);
+                let [name, register] = operands.as_slice() else {
+                    return Err(".reg takes a name and a register".to_string());
+                };
+                let register = self.register(register)?;
+                self.names.insert(name.clone(), register);
+                return Ok(());
+            }
+            ".data" => {
+                out.push(format!(".word {}", &text[".data".len()..].trim()));
+                return Ok(());
+            }
+            // the native assembler's directives
+            _ if first.starts_with('.') => {
+                out.push(self.resolve_names(text));
+                return Ok(());
+            }
+            _ => {}
+        }
+        let mut temps = self.fresh_temps();
+        if let Some((target, value)) = split_assignment(text).filter(|_| first != "if") {
+            return self.assignment(target.trim(), value.trim(), &mut temps, out);
+        }
+        // unmap is a native instruction too when it names no m[X]
+        let native_unmap = first == "unmap" && !matches!(words.as_slice(), [_, target] if segment_operand(target).is_some());
+        if !MACRO_KEYWORDS.contains(&first) || native_unmap {
+            out.push(self.resolve_names(text));
+            return Ok(());
+        }
+        match words.as_slice() {
+            [_, value] if first == "output" => {
+                let value = self.operand(value);
+                let c = self.materialize(&value, &mut temps, out)?;
+                out.push(format!("out r{}", c));
+            }
+            [_, target] if first == "unmap" => {
+                let segment = self.operand(segment_operand(target).ok_or("unmap takes m[X]")?);
+                let c = self.materialize(&segment, &mut temps, out)?;
+                out.push(format!("unmap r{}", c));
+            }
+            [_, target] if first == "goto" => {
+                let target = self.operand(target);
+                let c = self.materialize(&target, &mut temps, out)?;
+                let zero = self.zero_register(&mut temps, out)?;
+                out.push(format!("loadprog r{}, r{}", zero, c));
+            }
+            [_, target, in_, program, segment] if first == "goto" && in_ == "in" && program == "program" => {
+                let c = self.register(target)?;
+                let b = self.register(segment_operand(segment).ok_or("goto X in program takes m[rB]")?)?;
+                self.check_operands(&[b, c])?;
+                out.push(format!("loadprog r{}, r{}", b, c));
+            }
+            [_, value, on, stack, sp] if first == "push" && on == "on" && stack == "stack" => {
+                let sp = self.register(sp)?;
+                let value = self.operand(value);
+                if let Operand::Register(r) = value {
+                    self.check_operands(&[r, sp])?;
+                }
+                let t = temps.take()?;
+                out.push(format!("loadval r{}, 0", t));
+                out.push(format!("nand r{}, r{}, r{}", t, t, t));
+                out.push(format!("add r{}, r{}, r{}", sp, sp, t));
+                temps.give(t);
+                let c = self.materialize(&value, &mut temps, out)?;
+                let zero = self.zero_register(&mut temps, out)?;
+                out.push(format!("store r{}, r{}, r{}", zero, sp, c));
+            }
+            [_, target, off, stack, sp] if first == "pop" && off == "off" && stack == "stack" => {
+                let a = self.register(target)?;
+                let sp = self.register(sp)?;
+                self.check_operands(&[a, sp])?;
+                let zero = self.zero_register(&mut temps, out)?;
+                out.push(format!("load r{}, r{}, r{}", a, zero, sp));
+                self.increment(sp, &mut temps, out)?;
+            }
+            [_, stack, sp] if first == "pop" && stack == "stack" => {
+                let sp = self.register(sp)?;
+                self.check_operands(&[sp])?;
+                self.increment(sp, &mut temps, out)?;
+            }
+            _ if first == "if" => self.conditional(text, &mut temps, out)?,
+            _ => return Err(format!("cannot parse {:?}", text)),
+        }
+        Ok(())
+    }
+
+    // Puts an operand in a register, loading constants into a temporary.
+    fn materialize(&self, operand: &Operand, temps: &mut Temps, out: &mut Vec<String>) -> Result<u8, String> {
+        match operand {
+            Operand::Register(r) => {
+                self.check_operands(&[*r])?;
+                Ok(*r)
+            }
+            Operand::Constant(value) => {
+                let t = temps.take()?;
+                self.load_constant(t, value, temps, out)?;
+                Ok(t)
+            }
+        }
+    }
+
+    // Loads any 32-bit constant, which takes more than a LoadVal once it
+    // needs more than 25 bits.
+    fn load_constant(&self, register: u8, value: &str, temps: &mut Temps, out: &mut Vec<String>) -> Result<(), String> {
+        match asm::evaluate(value, &HashMap::new()) {
+            Ok(v) if v >= 1 << 25 && !v < 1 << 25 => {
+                out.push(format!("loadval r{}, {}", register, !v));
+                out.push(format!("nand r{}, r{}, r{}", register, register, register));
+            }
+            Ok(v) if v >= 1 << 25 => {
+                let t = temps.take()?;
+                out.push(format!("loadval r{}, {}", register, v >> 16));
+                out.push(format!("loadval r{}, 65536", t));
+                out.push(format!("mul r{}, r{}, r{}", register, register, t));
+                out.push(format!("loadval r{}, {}", t, v & 0xffff));
+                out.push(format!("add r{}, r{}, r{}", register, register, t));
+                temps.give(t);
+            }
+            _ => out.push(format!("loadval r{}, {}", register, value)),
+        }
+        Ok(())
+    }
+
+    fn zero_register(&self, temps: &mut Temps, out: &mut Vec<String>) -> Result<u8, String> {
+        match self.zero {
+            Some(zero) => Ok(zero),
+            None => {
+                let t = temps.take()?;
+                out.push(format!("loadval r{}, 0", t));
+                Ok(t)
+            }
+        }
+    }
+
+    // loadprog to the address in `register`, leaving the temporaries as they were
+    fn jump(&self, register: u8, temps: &mut Temps, out: &mut Vec<String>) -> Result<(), String> {
+        let zero = self.zero_register(temps, out)?;
+        out.push(format!("loadprog r{}, r{}", zero, register));
+        if self.zero != Some(zero) {
+            temps.give(zero);
+        }
+        Ok(())
+    }
+
+    fn increment(&self, register: u8, temps: &mut Temps, out: &mut Vec<String>) -> Result<(), String> {
+        let t = temps.take()?;
+        out.push(format!("loadval r{}, 1", t));
+        out.push(format!("add r{}, r{}, r{}", register, register, t));
+        temps.give(t);
+        Ok(())
+    }
+
+    fn assignment(&mut self, target: &str, value: &str, temps: &mut Temps, out: &mut Vec<String>) -> Result<(), String> {
+        if let Some((segment, offset)) = memory_operands(target) {
+            let (segment, offset, value) = (self.operand(segment), self.operand(offset), self.operand(value));
+            let a = self.materialize(&segment, temps, out)?;
+            let b = self.materialize(&offset, temps, out)?;
+            let c = self.materialize(&value, temps, out)?;
+            out.push(format!("store r{}, r{}, r{}", a, b, c));
+            return Ok(());
+        }
+        let a = self.register(target)?;
+        self.check_operands(&[a])?;
+        if value == "input()" {
+            out.push(format!("in r{}", a));
+            return Ok(());
+        }
+        if let Some((segment, offset)) = memory_operands(value) {
+            let (segment, offset) = (self.operand(segment), self.operand(offset));
+            let b = self.materialize(&segment, temps, out)?;
+            let c = self.materialize(&offset, temps, out)?;
+            out.push(format!("load r{}, r{}, r{}", a, b, c));
+            return Ok(());
+        }
+        if let Some(len) = value.strip_prefix("map segment (").and_then(|v| v.strip_suffix("words)")) {
+            let len = self.operand(len);
+            let c = self.materialize(&len, temps, out)?;
+            out.push(format!("map r{}, r{}", a, c));
+            return Ok(());
+        }
+        let words = tokens(value);
+        match words.as_slice() {
+            [x] if x.starts_with('~') => {
+                let b = self.materialize(&self.operand(&x[1..]), temps, out)?;
+                out.push(format!("nand r{}, r{}, r{}", a, b, b));
+            }
+            [x] if x.starts_with('-') && !x[1..].starts_with(|c: char| c.is_ascii_digit()) => {
+                let b = self.materialize(&self.operand(&x[1..]), temps, out)?;
+                out.push(format!("nand r{}, r{}, r{}", a, b, b));
+                self.increment(a, temps, out)?;
+            }
+            [x] => match self.operand(x) {
+                Operand::Register(b) if b == a => {}
+                Operand::Register(b) => {
+                    self.check_operands(&[b])?;
+                    let zero = self.zero_register(temps, out)?;
+                    out.push(format!("add r{}, r{}, r{}", a, b, zero));
+                }
+                Operand::Constant(value) => self.load_constant(a, &value, temps, out)?,
+            },
+            [x, op, y] => {
+                let (x, y) = (self.operand(x), self.operand(y));
+                let b = self.materialize(&x, temps, out)?;
+                let c = self.materialize(&y, temps, out)?;
+                match op.as_str() {
+                    "+" => out.push(format!("add r{}, r{}, r{}", a, b, c)),
+                    "*" => out.push(format!("mul r{}, r{}, r{}", a, b, c)),
+                    "/" => out.push(format!("div r{}, r{}, r{}", a, b, c)),
+                    "nand" => out.push(format!("nand r{}, r{}, r{}", a, b, c)),
+                    "-" => {
+                        let t = temps.take()?;
+                        out.push(format!("nand r{}, r{}, r{}", t, c, c));
+                        out.push(format!("add r{}, r{}, r{}", a, b, t));
+                        temps.give(t);
+                        self.increment(a, temps, out)?;
+                    }
+                    "&" => {
+                        let t = temps.take()?;
+                        out.push(format!("nand r{}, r{}, r{}", t, b, c));
+                        out.push(format!("nand r{}, r{}, r{}", a, t, t));
+                    }
+                    "|" => {
+                        // ~y goes where a constant y already is, and first,
+                        // in case y is the destination
+                        let t = if self.temps.contains(&c) { c } else { temps.take()? };
+                        out.push(format!("nand r{}, r{}, r{}", t, c, c));
+                        out.push(format!("nand r{}, r{}, r{}", a, b, b));
+                        out.push(format!("nand r{}, r{}, r{}", a, a, t));
+                    }
+                    _ => return Err(format!("unknown operator {}", op)),
+                }
+            }
+            _ => return Err(format!("cannot parse {:?}", value)),
+        }
+        Ok(())
+    }
+
+    // if (COND) goto L, and if (rC) rA := X
+    fn conditional(&mut self, text: &str, temps: &mut Temps, out: &mut Vec<String>) -> Result<(), String> {
+        let rest = text["if".len()..].trim_start();
+        let close = rest.find(')').filter(|_| rest.starts_with('(')).ok_or("expected if (condition)")?;
+        let condition = tokens(&rest[1..close]);
+        let action = rest[close + 1..].trim();
+        // reduce the condition to a register that is nonzero exactly when it holds
+        let (register, holds_when_zero) = match condition.as_slice() {
+            [x] => (self.materialize(&self.operand(x), temps, out)?, false),
+            [x, op, y] if op == "==" || op == "!=" => {
+                let x = self.operand(x);
+                let y = self.operand(y);
+                let is_zero = |o: &Operand| matches!(o, Operand::Constant(c) if asm::evaluate(c, &HashMap::new()) == Ok(0));
+                let register = if is_zero(&y) {
+                    self.materialize(&x, temps, out)?
+                } else {
+                    // x - y is nonzero exactly when they differ, and goes
+                    // where a constant operand already is
+                    let b = self.materialize(&x, temps, out)?;
+                    let c = self.materialize(&y, temps, out)?;
+                    let d = match [c, b].into_iter().find(|r| self.temps.contains(r)) {
+                        Some(d) => d,
+                        None => temps.take()?,
+                    };
+                    out.push(format!("nand r{}, r{}, r{}", d, c, c));
+                    out.push(format!("add r{}, r{}, r{}", d, d, b));
+                    for r in [b, c] {
+                        if r != d && self.temps.contains(&r) {
+                            temps.give(r);
+                        }
+                    }
+                    self.increment(d, temps, out)?;
+                    d
+                };
+                (register, op == "==")
+            }
+            _ => return Err(format!("cannot parse condition {:?}", &rest[1..close])),
+        };
+        if let Some(target) = action.strip_prefix("goto ") {
+            let target = target.trim();
+            let next = self.label();
+            if !self.temps.contains(&register) {
+                let (t, u) = (temps.take()?, temps.take()?);
+                let (if_zero, if_nonzero) = if holds_when_zero { (target, next.as_str()) } else { (next.as_str(), target) };
+                out.push(format!("loadval r{}, {}", t, if_zero));
+                out.push(format!("loadval r{}, {}", u, if_nonzero));
+                out.push(format!("cmov r{}, r{}, r{}", t, u, register));
+                temps.give(u);
+                let zero = self.zero_register(temps, out)?;
+                out.push(format!("loadprog r{}, r{}", zero, t));
+                out.push(format!("{}:", next));
+                return Ok(());
+            }
+            // the condition is in a temporary, which picks the address in
+            // two moves: first the nonzero case's into itself, which has to
+            // be a generated label since a label of the program may be 0,
+            // then itself into the other temporary
+            let d = register;
+            let t = temps.take()?;
+            let taken = if holds_when_zero { None } else { Some(self.label()) };
+            let (if_zero, if_nonzero) = match &taken {
+                Some(taken) => (next.as_str(), taken.as_str()),
+                None => (target, next.as_str()),
+            };
+            out.push(format!("loadval r{}, {}", t, if_nonzero));
+            out.push(format!("cmov r{}, r{}, r{}", d, t, d));
+            out.push(format!("loadval r{}, {}", t, if_zero));
+            out.push(format!("cmov r{}, r{}, r{}", t, d, d));
+            temps.give(d);
+            self.jump(t, temps, out)?;
+            if let Some(taken) = taken {
+                out.push(format!("{}:", taken));
+                out.push(format!("loadval r{}, {}", t, target));
+                self.jump(t, temps, out)?;
+            }
+            out.push(format!("{}:", next));
+            return Ok(());
+        }
+        if let Some((target, value)) = action.split_once(":=") {
+            if holds_when_zero {
+                return Err("a conditional move needs a condition that holds when a register is nonzero".to_string());
+            }
+            let a = self.register(target)?;
+            self.check_operands(&[a])?;
+            let b = self.materialize(&self.operand(value), temps, out)?;
+            out.push(format!("cmov r{}, r{}, r{}", a, b, register));
+            return Ok(());
+        }
+        Err(format!("cannot parse {:?}", action))
+    }
+}